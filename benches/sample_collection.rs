@@ -0,0 +1,72 @@
+//! Two ways for concurrent clients to hand their raw samples to the aggregator: a lock taken
+//! per sample on one shared `Vec`, and what `simulate_client` does, keeping each client's
+//! samples in its own `Vec` and handing the whole batch over once, with its report.
+//!
+//! Run with `cargo bench`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::hint::black_box;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+const SAMPLES_PER_CLIENT: usize = 10_000;
+
+fn price(client: usize, sample: usize) -> f64 {
+    43_000.0 + (client * SAMPLES_PER_CLIENT + sample) as f64 * 0.01
+}
+
+/// Every client pushes each sample into the shared `Vec` under its lock.
+fn per_sample_lock(clients: usize) -> Vec<f64> {
+    let shared = Arc::new(Mutex::new(Vec::new()));
+    let handles: Vec<_> = (0..clients)
+        .map(|client| {
+            let shared = Arc::clone(&shared);
+            thread::spawn(move || {
+                for sample in 0..SAMPLES_PER_CLIENT {
+                    shared.lock().unwrap().push(price(client, sample));
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    Arc::try_unwrap(shared).unwrap().into_inner().unwrap()
+}
+
+/// Every client fills its own `Vec` and takes the lock once to flush it.
+fn batched_flush(clients: usize) -> Vec<f64> {
+    let shared = Arc::new(Mutex::new(Vec::new()));
+    let handles: Vec<_> = (0..clients)
+        .map(|client| {
+            let shared = Arc::clone(&shared);
+            thread::spawn(move || {
+                let batch: Vec<f64> = (0..SAMPLES_PER_CLIENT).map(|sample| price(client, sample)).collect();
+                shared.lock().unwrap().extend(batch);
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    Arc::try_unwrap(shared).unwrap().into_inner().unwrap()
+}
+
+fn sample_collection(c: &mut Criterion) {
+    let mut group = c.benchmark_group("sample_collection");
+    for clients in [1, 5, 20, 100] {
+        group.throughput(Throughput::Elements((clients * SAMPLES_PER_CLIENT) as u64));
+        group.bench_with_input(BenchmarkId::new("per_sample_lock", clients), &clients, |b, &clients| {
+            b.iter(|| black_box(per_sample_lock(clients)))
+        });
+        group.bench_with_input(BenchmarkId::new("batched_flush", clients), &clients, |b, &clients| {
+            b.iter(|| black_box(batched_flush(clients)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, sample_collection);
+criterion_main!(benches);
@@ -0,0 +1,733 @@
+use chrono::{DateTime, Utc};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use tracing::warn;
+
+/// What a single client sends to the aggregator once its loop ends.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ClientReport {
+    pub client_id: usize,
+    pub average: f64,
+    pub samples: usize,
+    /// The raw samples; only needed for percentiles and VWAP, so not persisted with the record.
+    #[serde(skip)]
+    pub prices: Vec<Sample>,
+    /// Successful samples keyed by the price source that supplied them.
+    #[serde(default)]
+    pub samples_by_source: BTreeMap<String, usize>,
+    #[serde(default)]
+    pub requests: RequestStats,
+    /// Wall-clock span of the client's polling loop, to tell early exits from full runs.
+    #[serde(default)]
+    pub started_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub finished_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub duration_secs: f64,
+    /// The interval and timeout this client ran with, which differ per client under `--clients-vary`.
+    #[serde(default)]
+    pub interval_ms: u64,
+    #[serde(default)]
+    pub timeout_ms: u64,
+    /// What ended the client's loop; records from before `--max-samples` all ran their window.
+    #[serde(default)]
+    pub stopped_by: StopReason,
+    /// Why the client stopped before its window ended; its samples up to then still count.
+    #[serde(default)]
+    pub error: Option<String>,
+    /// With `--compare-sources`, what each source returned and how far apart they were.
+    #[serde(default)]
+    pub source_spread: Option<Box<SourceSpread>>,
+}
+
+/// One price reading, with the traded volume behind it when the source reports one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sample {
+    pub price: f64,
+    pub volume: Option<f64>,
+}
+
+impl Sample {
+    /// A reading from a source that reports no volume.
+    pub fn price(price: f64) -> Self {
+        Sample { price, volume: None }
+    }
+}
+
+/// How far the price sources disagreed under `--compare-sources`, which asks each of them on
+/// every tick.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SourceSpread {
+    /// Mean price per source over the ticks it answered.
+    pub by_source: BTreeMap<String, SourceAverage>,
+    /// Widest gap between the highest and lowest answer on a single tick, as a percentage of
+    /// the lowest.
+    pub max_spread_percent: f64,
+    /// Ticks where at least two sources answered, so there was a spread to measure.
+    pub compared_ticks: usize,
+    /// Compared ticks whose spread was over `threshold_percent`.
+    pub disagreements: usize,
+    pub threshold_percent: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SourceAverage {
+    pub mean: f64,
+    pub samples: usize,
+}
+
+impl SourceSpread {
+    /// Folds another client's spread into this one, weighting each source's mean by its samples.
+    fn merge(&mut self, other: &SourceSpread) {
+        for (source, theirs) in &other.by_source {
+            let ours = self.by_source.entry(source.clone()).or_insert(SourceAverage { mean: 0.0, samples: 0 });
+            let samples = ours.samples + theirs.samples;
+            if samples > 0 {
+                ours.mean = (ours.mean * ours.samples as f64 + theirs.mean * theirs.samples as f64) / samples as f64;
+            }
+            ours.samples = samples;
+        }
+        self.max_spread_percent = self.max_spread_percent.max(other.max_spread_percent);
+        self.compared_ticks += other.compared_ticks;
+        self.disagreements += other.disagreements;
+    }
+}
+
+/// Which limit ended a client's loop.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StopReason {
+    /// The `--times` window closed.
+    #[default]
+    Window,
+    /// It collected `--max-samples` samples first.
+    MaxSamples,
+    /// `--once` took its single reading.
+    Once,
+    /// Ctrl-C or `--timeout-total` cut it short.
+    Shutdown,
+    /// An unrecoverable error; see `ClientReport::error`.
+    Error,
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            StopReason::Window => "window",
+            StopReason::MaxSamples => "max-samples",
+            StopReason::Once => "once",
+            StopReason::Shutdown => "shutdown",
+            StopReason::Error => "error",
+        })
+    }
+}
+
+/// What a client sends to the aggregator once its loop ends.
+#[derive(Debug, Clone)]
+pub enum ClientMessage {
+    Report(ClientReport),
+    /// The client never got a price, so it has no average, but its failures still count.
+    NoSamples { client_id: usize, requests: RequestStats },
+}
+
+/// How each tick's fetch ended, counted per client and summed for the run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RequestStats {
+    pub ok: usize,
+    /// Connection failures and error statuses, after retries.
+    pub http_errors: usize,
+    pub timeouts: usize,
+    /// Connection failures reaching the configured proxy rather than the API.
+    #[serde(default)]
+    pub proxy_errors: usize,
+    /// Responses that arrived but held no usable price.
+    pub parse_errors: usize,
+}
+
+/// How a single tick's fetch ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Ok,
+    HttpError,
+    Timeout,
+    ProxyError,
+    ParseError,
+}
+
+impl Outcome {
+    pub fn label(self) -> &'static str {
+        match self {
+            Outcome::Ok => "ok",
+            Outcome::HttpError => "http_error",
+            Outcome::Timeout => "timeout",
+            Outcome::ProxyError => "proxy_error",
+            Outcome::ParseError => "parse_error",
+        }
+    }
+}
+
+impl RequestStats {
+    pub fn record(&mut self, outcome: Outcome) {
+        match outcome {
+            Outcome::Ok => self.ok += 1,
+            Outcome::HttpError => self.http_errors += 1,
+            Outcome::Timeout => self.timeouts += 1,
+            Outcome::ProxyError => self.proxy_errors += 1,
+            Outcome::ParseError => self.parse_errors += 1,
+        }
+    }
+
+    pub fn failures(&self) -> usize {
+        self.http_errors + self.timeouts + self.proxy_errors + self.parse_errors
+    }
+
+    pub fn add(&mut self, other: &RequestStats) {
+        self.ok += other.ok;
+        self.http_errors += other.http_errors;
+        self.timeouts += other.timeouts;
+        self.proxy_errors += other.proxy_errors;
+        self.parse_errors += other.parse_errors;
+    }
+}
+
+impl fmt::Display for RequestStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ok, {} http errors, {} timeouts, {} parse errors",
+            self.ok, self.http_errors, self.timeouts, self.parse_errors
+        )?;
+        if self.proxy_errors > 0 {
+            write!(f, ", {} proxy errors", self.proxy_errors)?;
+        }
+        Ok(())
+    }
+}
+
+/// How the client averages are combined into the headline aggregate.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(tag = "method", rename_all = "lowercase")]
+pub enum AggregateMethod {
+    #[default]
+    Mean,
+    Median,
+    /// Mean after dropping `percent` of the averages from each end.
+    Trimmed { percent: f64 },
+    /// Every sample weighted by its traded volume, rather than each client average equally.
+    Vwap,
+}
+
+impl fmt::Display for AggregateMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AggregateMethod::Mean => write!(f, "mean"),
+            AggregateMethod::Median => write!(f, "median"),
+            AggregateMethod::Trimmed { percent } => write!(f, "{}% trimmed mean", percent),
+            AggregateMethod::Vwap => write!(f, "volume-weighted average price"),
+        }
+    }
+}
+
+/// Cross-client settings for how the final number is derived.
+#[derive(Debug, Clone, Default)]
+pub struct AggregationConfig {
+    pub method: AggregateMethod,
+    /// Discard client averages more than this many standard deviations from the mean.
+    pub reject_outliers: Option<f64>,
+    /// Fold reports in an order fixed by this seed rather than by arrival, so floating-point
+    /// sums repeat exactly between runs.
+    pub report_order_seed: Option<u64>,
+    /// A final aggregate outside these bounds is flagged as suspicious.
+    pub min_price: Option<f64>,
+    pub max_price: Option<f64>,
+    /// Reports the aggregator's channel holds before clients have to wait; `None` gives every
+    /// client a slot.
+    pub channel_capacity: Option<usize>,
+}
+
+impl AggregationConfig {
+    /// Whether `aggregate` falls outside `--min-price`/`--max-price`.
+    pub fn is_out_of_bounds(&self, aggregate: f64) -> bool {
+        self.min_price.is_some_and(|min| aggregate < min) || self.max_price.is_some_and(|max| aggregate > max)
+    }
+}
+
+/// Result of `calculate_trimmed_aggregate`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TrimmedAggregate {
+    pub value: f64,
+    pub rejected: usize,
+}
+
+/// Percentiles over every raw sample of a run, rather than over client averages.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SamplePercentiles {
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+}
+
+/// Below this many clients the standard deviation says too little to call anything an outlier.
+const MIN_CLIENTS_FOR_OUTLIERS: usize = 3;
+
+#[derive(Debug)]
+pub struct AggregatorData {
+    reports: Vec<ClientReport>,
+    /// Request outcomes over every client, including those that never reported an average.
+    requests: RequestStats,
+}
+
+impl AggregatorData {
+    pub fn new() -> Self {
+        AggregatorData {
+            reports: Vec::new(),
+            requests: RequestStats::default(),
+        }
+    }
+
+    pub fn add_message(&mut self, message: ClientMessage) {
+        match message {
+            ClientMessage::Report(report) => self.add_report(report),
+            ClientMessage::NoSamples { requests, .. } => self.requests.add(&requests),
+        }
+    }
+
+    pub fn add_report(&mut self, report: ClientReport) {
+        self.requests.add(&report.requests);
+        self.reports.push(report);
+    }
+
+    /// Puts the reports into a seed-determined order, independent of which client the
+    /// scheduler happened to finish first.
+    pub fn reorder(&mut self, seed: u64) {
+        self.reports.sort_by_key(|report| report.client_id);
+        self.reports.shuffle(&mut StdRng::seed_from_u64(seed));
+    }
+
+    pub fn requests(&self) -> RequestStats {
+        self.requests
+    }
+
+    pub fn reports(&self) -> &[ClientReport] {
+        &self.reports
+    }
+
+    /// Number of clients that reported an average.
+    pub fn len(&self) -> usize {
+        self.reports.len()
+    }
+
+    pub fn total_samples(&self) -> usize {
+        self.reports.iter().map(|report| report.samples).sum()
+    }
+
+    pub fn calculate_final_aggregate(&self) -> f64 {
+        if self.reports.is_empty() {
+            0.0
+        } else {
+            sum_kahan(self.sorted_averages()) / self.reports.len() as f64
+        }
+    }
+
+    /// Mean of client averages weighted by how many samples each client took.
+    pub fn calculate_weighted_aggregate(&self) -> Option<f64> {
+        let total_samples = self.total_samples();
+        if total_samples == 0 {
+            return None;
+        }
+
+        let mut weighted: Vec<f64> = self
+            .reports
+            .iter()
+            .map(|report| report.average * report.samples as f64)
+            .collect();
+        weighted.sort_by(|a, b| a.total_cmp(b));
+
+        Some(sum_kahan(weighted) / total_samples as f64)
+    }
+
+    /// Mean of client averages after dropping those more than `max_std_devs` from the mean.
+    /// Falls back to the plain mean when there are too few clients or nothing would remain.
+    pub fn calculate_trimmed_aggregate(&self, max_std_devs: f64) -> TrimmedAggregate {
+        let mean = self.calculate_final_aggregate();
+        let fallback = TrimmedAggregate { value: mean, rejected: 0 };
+
+        if self.reports.len() < MIN_CLIENTS_FOR_OUTLIERS {
+            return fallback;
+        }
+        let Some(std_dev) = self.std_dev() else {
+            return fallback;
+        };
+
+        let kept: Vec<f64> = self
+            .sorted_averages()
+            .into_iter()
+            .filter(|average| (average - mean).abs() <= max_std_devs * std_dev)
+            .collect();
+
+        if kept.is_empty() {
+            return fallback;
+        }
+
+        TrimmedAggregate {
+            value: sum_kahan(kept.iter().copied()) / kept.len() as f64,
+            rejected: self.reports.len() - kept.len(),
+        }
+    }
+
+    /// The headline aggregate under `method`; 0.0 when no client reported.
+    pub fn aggregate_by(&self, method: AggregateMethod) -> f64 {
+        let value = match method {
+            AggregateMethod::Mean => Some(self.calculate_final_aggregate()),
+            AggregateMethod::Median => self.median(),
+            AggregateMethod::Trimmed { percent } => self.trimmed_mean(percent),
+            AggregateMethod::Vwap => self.calculate_vwap(),
+        };
+        value.unwrap_or(0.0)
+    }
+
+    /// `method`, unless it is VWAP and no sample carried a volume, in which case the run falls
+    /// back to the mean with a warning.
+    pub fn resolve_method(&self, method: AggregateMethod) -> AggregateMethod {
+        if method != AggregateMethod::Vwap || self.reports.is_empty() {
+            return method;
+        }
+
+        if self.calculate_vwap().is_none() {
+            warn!("No sample reported a traded volume, falling back to the mean for --aggregate=vwap");
+            return AggregateMethod::Mean;
+        }
+        let without_volume = self.samples().filter(|sample| sample.volume.is_none()).count();
+        if without_volume > 0 {
+            warn!(
+                "VWAP left out {} of {} samples, their source reported no volume",
+                without_volume,
+                self.samples().count()
+            );
+        }
+        method
+    }
+
+    /// Sum of price times volume over the total volume, across every sample of every client
+    /// that reported one. `None` when no sample did, or their volumes add up to zero.
+    pub fn calculate_vwap(&self) -> Option<f64> {
+        let mut weighted = Vec::new();
+        let mut volumes = Vec::new();
+        for sample in self.samples() {
+            if let Some(volume) = sample.volume {
+                weighted.push(sample.price * volume);
+                volumes.push(volume);
+            }
+        }
+        weighted.sort_by(|a, b| a.total_cmp(b));
+        volumes.sort_by(|a, b| a.total_cmp(b));
+
+        let total_volume = sum_kahan(volumes);
+        (total_volume > 0.0).then(|| sum_kahan(weighted) / total_volume)
+    }
+
+    /// Mean of the client averages left after dropping `percent` of them from each end, rounding
+    /// the count dropped down so small runs keep every average.
+    pub fn trimmed_mean(&self, percent: f64) -> Option<f64> {
+        if self.reports.is_empty() {
+            return None;
+        }
+
+        let sorted = self.sorted_averages();
+        let drop = (sorted.len() as f64 * percent / 100.0).floor() as usize;
+        let kept = &sorted[drop..sorted.len() - drop];
+
+        Some(sum_kahan(kept.iter().copied()) / kept.len() as f64)
+    }
+
+    /// Every client's `--compare-sources` spread combined; `None` when the mode was off.
+    pub fn source_spread(&self) -> Option<SourceSpread> {
+        let mut spreads = self.reports.iter().filter_map(|report| report.source_spread.as_ref());
+        let mut combined = SourceSpread::clone(spreads.next()?);
+        for spread in spreads {
+            combined.merge(spread);
+        }
+        Some(combined)
+    }
+
+    /// Nearest-rank percentiles over the combined samples of every client.
+    pub fn sample_percentiles(&self) -> Option<SamplePercentiles> {
+        let mut prices: Vec<f64> = self.samples().map(|sample| sample.price).collect();
+        if prices.is_empty() {
+            return None;
+        }
+        prices.sort_by(|a, b| a.total_cmp(b));
+
+        let rank = |percentile: f64| {
+            let index = (percentile / 100.0 * prices.len() as f64).ceil() as usize;
+            prices[index.clamp(1, prices.len()) - 1]
+        };
+
+        Some(SamplePercentiles {
+            p50: rank(50.0),
+            p90: rank(90.0),
+            p99: rank(99.0),
+        })
+    }
+
+    pub fn median(&self) -> Option<f64> {
+        if self.reports.is_empty() {
+            return None;
+        }
+
+        let sorted = self.sorted_averages();
+        let mid = sorted.len() / 2;
+
+        if sorted.len().is_multiple_of(2) {
+            Some((sorted[mid - 1] + sorted[mid]) / 2.0)
+        } else {
+            Some(sorted[mid])
+        }
+    }
+
+    pub fn min(&self) -> Option<f64> {
+        self.averages().reduce(f64::min)
+    }
+
+    pub fn max(&self) -> Option<f64> {
+        self.averages().reduce(f64::max)
+    }
+
+    /// Population standard deviation of the client averages.
+    pub fn std_dev(&self) -> Option<f64> {
+        if self.reports.len() < 2 {
+            return None;
+        }
+
+        let mean = self.calculate_final_aggregate();
+        let squared_deviations = self.sorted_averages().into_iter().map(|average| (average - mean).powi(2));
+        let variance = sum_kahan(squared_deviations) / self.reports.len() as f64;
+
+        Some(variance.sqrt())
+    }
+
+    fn samples(&self) -> impl Iterator<Item = &Sample> + '_ {
+        self.reports.iter().flat_map(|report| report.prices.iter())
+    }
+
+    fn averages(&self) -> impl Iterator<Item = f64> + '_ {
+        self.reports.iter().map(|report| report.average)
+    }
+
+    fn sorted_averages(&self) -> Vec<f64> {
+        let mut sorted: Vec<f64> = self.averages().collect();
+        sorted.sort_by(|a, b| a.total_cmp(b));
+        sorted
+    }
+}
+
+/// Compensated (Neumaier) sum: the rounding error of each addition is carried along and added
+/// back at the end. Callers sort their values first, so the result is also the same whichever
+/// order the clients reported in.
+fn sum_kahan(values: impl IntoIterator<Item = f64>) -> f64 {
+    let mut sum = 0.0;
+    let mut compensation = 0.0;
+
+    for value in values {
+        let total = sum + value;
+        compensation += if f64::abs(sum) >= f64::abs(value) {
+            (sum - total) + value
+        } else {
+            (value - total) + sum
+        };
+        sum = total;
+    }
+
+    sum + compensation
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(client_id: usize, average: f64, samples: usize) -> ClientReport {
+        ClientReport {
+            client_id,
+            average,
+            samples,
+            ..ClientReport::default()
+        }
+    }
+
+    fn data_with_averages(averages: &[f64]) -> AggregatorData {
+        let mut data = AggregatorData::new();
+        for (index, &average) in averages.iter().enumerate() {
+            data.add_report(report(index + 1, average, 1));
+        }
+        data
+    }
+
+    #[test]
+    fn std_dev_is_the_population_standard_deviation() {
+        let data = data_with_averages(&[2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0]);
+
+        assert_eq!(data.std_dev(), Some(2.0));
+    }
+
+    #[test]
+    fn std_dev_needs_two_clients() {
+        assert_eq!(data_with_averages(&[]).std_dev(), None);
+        assert_eq!(data_with_averages(&[42.0]).std_dev(), None);
+    }
+
+    #[test]
+    fn weighted_aggregate_follows_the_client_with_more_samples() {
+        let mut data = AggregatorData::new();
+        data.add_report(report(1, 100.0, 9));
+        data.add_report(report(2, 200.0, 1));
+
+        assert_eq!(data.calculate_final_aggregate(), 150.0);
+        assert_eq!(data.calculate_weighted_aggregate(), Some(110.0));
+    }
+
+    #[test]
+    fn weighted_aggregate_needs_a_sample() {
+        assert_eq!(AggregatorData::new().calculate_weighted_aggregate(), None);
+    }
+
+    #[test]
+    fn trimmed_aggregate_rejects_the_outlier() {
+        let data = data_with_averages(&[100.0, 101.0, 99.0, 100.0, 102.0, 100.0, 1000.0]);
+
+        let trimmed = data.calculate_trimmed_aggregate(2.0);
+
+        assert_eq!(trimmed.rejected, 1);
+        assert!((trimmed.value - 602.0 / 6.0).abs() < 1e-9, "got {}", trimmed.value);
+    }
+
+    #[test]
+    fn trimmed_aggregate_keeps_everything_below_the_minimum_client_count() {
+        let data = data_with_averages(&[100.0, 1000.0]);
+
+        let trimmed = data.calculate_trimmed_aggregate(0.5);
+
+        assert_eq!(trimmed.rejected, 0);
+        assert_eq!(trimmed.value, 550.0);
+    }
+
+    #[test]
+    fn reorder_gives_the_same_order_whichever_order_reports_arrived_in() {
+        let client_ids = |data: &AggregatorData| data.reports().iter().map(|r| r.client_id).collect::<Vec<_>>();
+        let mut arrived = AggregatorData::new();
+        let mut reversed = AggregatorData::new();
+        for client_id in 1..=8 {
+            arrived.add_report(report(client_id, 100.0 + client_id as f64, 1));
+            reversed.add_report(report(9 - client_id, 109.0 - client_id as f64, 1));
+        }
+
+        arrived.reorder(42);
+        reversed.reorder(42);
+
+        assert_eq!(client_ids(&arrived), client_ids(&reversed));
+        assert_eq!(arrived.calculate_final_aggregate(), reversed.calculate_final_aggregate());
+    }
+
+    #[test]
+    fn out_of_bounds_is_below_the_minimum_or_above_the_maximum() {
+        let bounds = AggregationConfig {
+            min_price: Some(50.0),
+            max_price: Some(150.0),
+            ..AggregationConfig::default()
+        };
+
+        assert!(bounds.is_out_of_bounds(49.99));
+        assert!(!bounds.is_out_of_bounds(50.0));
+        assert!(!bounds.is_out_of_bounds(150.0));
+        assert!(bounds.is_out_of_bounds(150.01));
+        assert!(!AggregationConfig::default().is_out_of_bounds(f64::MAX));
+    }
+
+    #[test]
+    fn sum_kahan_keeps_the_small_terms_a_naive_sum_loses() {
+        let values = [1e16, 1.0, -1e16];
+
+        assert_eq!(values.iter().sum::<f64>(), 0.0);
+        assert_eq!(sum_kahan(values), 1.0);
+    }
+
+    #[test]
+    fn the_aggregate_is_bit_identical_whatever_order_reports_arrive_in() {
+        let averages = [43210.17, 0.1, 43209.99, 0.2, 43211.03, 0.3, 1e-9];
+        let forward = data_with_averages(&averages);
+        let mut reversed_averages = averages;
+        reversed_averages.reverse();
+        let reversed = data_with_averages(&reversed_averages);
+
+        for method in [AggregateMethod::Mean, AggregateMethod::Median] {
+            assert_eq!(
+                forward.aggregate_by(method).to_bits(),
+                reversed.aggregate_by(method).to_bits(),
+                "{:?}",
+                method
+            );
+        }
+        assert_eq!(forward.std_dev().map(f64::to_bits), reversed.std_dev().map(f64::to_bits));
+    }
+
+    fn data_with_samples(clients: &[&[Sample]]) -> AggregatorData {
+        let mut data = AggregatorData::new();
+        for (index, samples) in clients.iter().enumerate() {
+            data.add_report(ClientReport {
+                prices: samples.to_vec(),
+                ..report(index + 1, 0.0, samples.len())
+            });
+        }
+        data
+    }
+
+    #[test]
+    fn vwap_weighs_each_price_by_its_volume_across_clients() {
+        let data = data_with_samples(&[
+            &[Sample { price: 100.0, volume: Some(1.0) }, Sample { price: 200.0, volume: Some(2.0) }],
+            &[Sample { price: 200.0, volume: Some(1.0) }, Sample::price(1000.0)],
+        ]);
+
+        // (100 * 1 + 200 * 2 + 200 * 1) / 4; the sample without a volume is left out
+        assert_eq!(data.calculate_vwap(), Some(175.0));
+        assert_eq!(data.resolve_method(AggregateMethod::Vwap), AggregateMethod::Vwap);
+        assert_eq!(data.aggregate_by(AggregateMethod::Vwap), 175.0);
+    }
+
+    #[test]
+    fn vwap_falls_back_to_the_mean_without_a_volume_to_weigh_by() {
+        let no_volume = data_with_samples(&[&[Sample::price(100.0)], &[Sample::price(200.0)]]);
+        let zero_volume = data_with_samples(&[&[Sample { price: 100.0, volume: Some(0.0) }]]);
+
+        assert_eq!(no_volume.calculate_vwap(), None);
+        assert_eq!(zero_volume.calculate_vwap(), None);
+        assert_eq!(no_volume.resolve_method(AggregateMethod::Vwap), AggregateMethod::Mean);
+        assert_eq!(AggregatorData::new().calculate_vwap(), None);
+    }
+
+    #[test]
+    fn std_dev_is_bit_identical_whatever_order_reports_arrive_in() {
+        // Magnitudes far enough apart that the order the squared deviations are summed in
+        // shows in the last bit
+        let averages = [
+            -20.0,
+            -5.960464477539063e-8,
+            -469762048.0,
+            20.0,
+            469762048.0,
+            5.960464477539063e-8,
+            -128.0,
+            128.0,
+        ];
+        let mut reversed_averages = averages;
+        reversed_averages.reverse();
+
+        assert_eq!(
+            data_with_averages(&averages).std_dev().map(f64::to_bits),
+            data_with_averages(&reversed_averages).std_dev().map(f64::to_bits)
+        );
+    }
+}
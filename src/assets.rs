@@ -0,0 +1,125 @@
+use crate::error::ClientError;
+use crate::output::write_atomically;
+use crate::source::{fetch_body, FetchError};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+/// How long a fetched asset list is shown again before the API is asked for a new one.
+const CACHE_MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// File the asset list is cached in, under the system temp directory.
+const CACHE_FILE: &str = "simulated_distributed_client-assets.json";
+
+/// One asset as the currencies endpoint lists it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Asset {
+    pub code: String,
+    pub name: String,
+}
+
+/// Coinbase's `{"data": [{"code": "BTC", "name": "Bitcoin", ...}, ...]}`; the other fields
+/// are ignored.
+#[derive(Debug, Deserialize)]
+struct CurrenciesResponse {
+    data: Vec<Asset>,
+}
+
+/// A fetched list, kept per endpoint so a staging host's list is never shown for production.
+#[derive(Debug, Serialize, Deserialize)]
+struct AssetCache {
+    endpoint: String,
+    fetched_at: DateTime<Utc>,
+    assets: Vec<Asset>,
+}
+
+impl AssetCache {
+    fn is_fresh(&self) -> bool {
+        (Utc::now() - self.fetched_at)
+            .to_std()
+            .is_ok_and(|age| age < CACHE_MAX_AGE)
+    }
+}
+
+pub fn assets_url(endpoint: &str) -> String {
+    format!("{}/v2/currencies/crypto", endpoint)
+}
+
+/// The assets `endpoint` supports, sorted by code, from the local cache while it is under a day
+/// old. When the request fails, an older cached list is still shown, with a warning, and only
+/// without any cached list is the failure an error.
+pub async fn list_assets(client: &reqwest::Client, endpoint: &str) -> Result<Vec<Asset>, ClientError> {
+    let path = cache_path();
+    let cached = read_cache(&path).filter(|cache| cache.endpoint == endpoint);
+    if let Some(cache) = cached.as_ref().filter(|cache| cache.is_fresh()) {
+        info!(
+            "Using the asset list cached in {} at {}",
+            path.display(),
+            cache.fetched_at.to_rfc3339()
+        );
+        return Ok(cache.assets.clone());
+    }
+
+    let url = assets_url(endpoint);
+    match fetch_assets(client, &url).await {
+        Ok(assets) => {
+            write_cache(&path, endpoint, &assets).await;
+            Ok(assets)
+        }
+        Err(e) => match cached {
+            Some(cache) => {
+                warn!(
+                    "Could not fetch the asset list from {} ({}), showing the one cached at {}",
+                    url,
+                    e,
+                    cache.fetched_at.to_rfc3339()
+                );
+                Ok(cache.assets)
+            }
+            None => Err(ClientError::AssetList { url, source: e }),
+        },
+    }
+}
+
+async fn fetch_assets(client: &reqwest::Client, url: &str) -> Result<Vec<Asset>, FetchError> {
+    let body = fetch_body(client.get(url)).await?;
+    let mut assets = serde_json::from_str::<CurrenciesResponse>(&body)?.data;
+    assets.sort_by(|a, b| a.code.cmp(&b.code));
+
+    Ok(assets)
+}
+
+fn cache_path() -> PathBuf {
+    std::env::temp_dir().join(CACHE_FILE)
+}
+
+/// A missing or unreadable cache is just no cache.
+fn read_cache(path: &Path) -> Option<AssetCache> {
+    let contents = std::fs::read_to_string(path).ok()?;
+    match serde_json::from_str(&contents) {
+        Ok(cache) => Some(cache),
+        Err(e) => {
+            debug!("Ignoring the unreadable asset cache {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// Failing to cache only costs a request next time, so it is logged rather than returned.
+/// Written atomically, as two list runs may overlap.
+async fn write_cache(path: &Path, endpoint: &str, assets: &[Asset]) {
+    let cache = AssetCache {
+        endpoint: endpoint.to_string(),
+        fetched_at: Utc::now(),
+        assets: assets.to_vec(),
+    };
+    let written = match serde_json::to_string(&cache) {
+        Ok(contents) => write_atomically(path, contents.as_bytes()).await.map_err(ClientError::from),
+        Err(e) => Err(ClientError::from(e)),
+    };
+    if let Err(e) = written {
+        warn!("Could not cache the asset list in {}: {}", path.display(), e);
+    }
+}
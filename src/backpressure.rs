@@ -0,0 +1,32 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// How long clients waited to hand their report to a full aggregator channel, so a slow
+/// aggregator under a small `--channel-capacity` shows up in the record.
+#[derive(Debug, Default)]
+pub struct Backpressure {
+    stalls: Mutex<BTreeMap<usize, Stall>>,
+}
+
+/// One client's waits on the full channel.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Stall {
+    pub count: usize,
+    pub blocked_secs: f64,
+}
+
+impl Backpressure {
+    pub fn record(&self, client_id: usize, blocked: Duration) {
+        let mut stalls = self.stalls.lock().expect("backpressure lock poisoned");
+        let stall = stalls.entry(client_id).or_default();
+        stall.count += 1;
+        stall.blocked_secs += blocked.as_secs_f64();
+    }
+
+    /// Every client that had to wait, by client id; clients that never did are left out.
+    pub fn stalls(&self) -> BTreeMap<usize, Stall> {
+        self.stalls.lock().expect("backpressure lock poisoned").clone()
+    }
+}
@@ -0,0 +1,62 @@
+use std::sync::Mutex;
+use tokio::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Trips after `threshold` consecutive failed ticks across all clients, then holds every client
+/// off for `cooldown`. Once the cooldown ends clients try again: a success closes the circuit,
+/// while a failure opens it again straight away rather than waiting for a fresh run of failures.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    threshold: usize,
+    cooldown: Duration,
+    state: Mutex<BreakerState>,
+}
+
+#[derive(Debug, Default)]
+struct BreakerState {
+    consecutive_failures: usize,
+    /// Set while open or half-open; cleared by the next success.
+    open_until: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: usize, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            threshold,
+            cooldown,
+            state: Mutex::new(BreakerState::default()),
+        }
+    }
+
+    /// How long callers must hold off before the next request, or `None` when they may go ahead.
+    pub fn wait_time(&self) -> Option<Duration> {
+        let state = self.state.lock().expect("circuit breaker lock poisoned");
+        let open_until = state.open_until?;
+        let now = Instant::now();
+
+        (open_until > now).then(|| open_until - now)
+    }
+
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().expect("circuit breaker lock poisoned");
+        if state.open_until.take().is_some() {
+            info!("Circuit breaker closed, requests resume normally");
+        }
+        state.consecutive_failures = 0;
+    }
+
+    pub fn record_failure(&self) {
+        let mut state = self.state.lock().expect("circuit breaker lock poisoned");
+        state.consecutive_failures += 1;
+
+        let now = Instant::now();
+        let already_open = state.open_until.is_some_and(|until| until > now);
+        if state.consecutive_failures >= self.threshold && !already_open {
+            warn!(
+                "Circuit breaker opened after {} consecutive failures, pausing all clients for {:?}",
+                state.consecutive_failures, self.cooldown
+            );
+            state.open_until = Some(now + self.cooldown);
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use crate::aggregator::Sample;
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::Mutex;
+use tokio::time::{Duration, Instant};
+use tracing::debug;
+
+/// Label recorded against samples served from the `--shared-cache` instead of a request.
+pub const CACHE_SOURCE: &str = "cache";
+
+/// One reading every client may reuse for `ttl`, so only the first tick in each TTL window
+/// reaches the API. The lock is held across the fetch: clients that tick while it is in flight
+/// wait for it and take its result as a hit instead of all fetching at once.
+#[derive(Debug)]
+pub struct PriceCache {
+    ttl: Duration,
+    entry: Mutex<Option<(Instant, Sample)>>,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+/// How often the `--shared-cache` answered a tick itself or had to fetch.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheStats {
+    pub hits: usize,
+    pub misses: usize,
+}
+
+impl PriceCache {
+    pub fn new(ttl: Duration) -> Self {
+        PriceCache {
+            ttl,
+            entry: Mutex::new(None),
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+
+    /// The cached sample while it is younger than the TTL, else the result of `fetch`, which is
+    /// cached when it succeeds. A failed fetch leaves the cache empty for the next caller.
+    pub async fn get_or_fetch<'a, E, F>(&self, fetch: F) -> Result<(Sample, &'a str), E>
+    where
+        F: Future<Output = Result<(Sample, &'a str), E>>,
+    {
+        let mut entry = self.entry.lock().await;
+        if let Some((fetched_at, sample)) = *entry {
+            if fetched_at.elapsed() < self.ttl {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Ok((sample, CACHE_SOURCE));
+            }
+        }
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let result = fetch.await;
+        match &result {
+            Ok((sample, _)) => *entry = Some((Instant::now(), *sample)),
+            Err(_) => {
+                debug!("Shared cache fetch failed, leaving the cache empty");
+                *entry = None;
+            }
+        }
+        result
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
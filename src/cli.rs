@@ -0,0 +1,930 @@
+use clap::builder::RangedU64ValueParser;
+use clap::{Parser, ValueEnum};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+use crate::aggregator::AggregateMethod;
+use crate::client::{redact_proxy, Averaging, SampleOutput, Transport, COINBASE_API_BASE, SAMPLE_LOG_TARGET};
+use crate::error::ClientError;
+use crate::output::Console;
+use crate::source::ResponseShape;
+use tracing::{info, warn};
+
+const EXIT_CODES_HELP: &str = "\
+Exit codes:
+  0  success
+  1  any other failure, e.g. an unwritable result file
+  2  invalid flags, config file or environment
+  3  no data: no client got a price, or no recorded run to read
+  4  the price API was unreachable or answered unusably, or --fail-fast stopped the run
+  5  --alert-change fired";
+
+/// Beyond this an f64 price only shows noise.
+pub const MAX_OUTPUT_PRECISION: u64 = 12;
+
+/// ISO 4217 fiat codes accepted by `--currency=`.
+pub const SUPPORTED_CURRENCIES: &[&str] = &["USD", "EUR", "GBP", "JPY", "CAD", "AUD", "CHF", "CNY", "INR"];
+pub const DEFAULT_CURRENCY: &str = "USD";
+/// Crypto assets accepted by `--asset=` unless overridden with `--allowed-assets=`.
+pub const DEFAULT_ALLOWED_ASSETS: &[&str] = &["BTC", "ETH", "SOL", "LTC", "DOGE", "ADA"];
+pub const DEFAULT_ASSET: &str = "BTC";
+pub const DEFAULT_TIMES_SECS: u64 = 10;
+/// Longest polling window `--times` accepts, one hour.
+pub const MAX_TIMES_SECS: u64 = 3600;
+/// Upper bound on `--clients=` so a typo can't hammer the API.
+pub const MAX_CLIENTS: u64 = 100;
+/// Polling faster than this risks a rate-limit ban from Coinbase.
+pub const MIN_INTERVAL_MS: u64 = 100;
+/// Slack `--timeout-total` allows past the polling window by default, for retries and jitter.
+pub const TOTAL_TIMEOUT_GRACE_SECS: u64 = 30;
+pub const DEFAULT_ADDR: &str = "127.0.0.1:8080";
+pub const DEFAULT_DB: &str = "history.db";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Mode {
+    /// Poll Coinbase with simulated clients and store the aggregate
+    Cache,
+    /// Print the stored aggregate
+    Read,
+    /// Serve the latest stored aggregate over HTTP
+    Serve,
+    /// Summarise every run in an appended history: range, mean and volatility
+    Stats,
+    /// List the asset codes --endpoint supports, for picking an --asset; cached for a day
+    ListAssets,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Human-readable summary in result.txt
+    Text,
+    /// One JSON record per line in result.txt
+    Json,
+    /// One row per run appended to result.csv
+    Csv,
+    /// GitHub-flavored Markdown report in result.md, also printed instead of the summary lines
+    Markdown,
+}
+
+impl OutputFormat {
+    pub fn default_path(self) -> &'static str {
+        match self {
+            OutputFormat::Text | OutputFormat::Json => "result.txt",
+            OutputFormat::Csv => "result.csv",
+            OutputFormat::Markdown => "result.md",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Store {
+    /// The --output/--input result file
+    File,
+    /// A SQLite history database at --db
+    Sqlite,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum AvgMethod {
+    /// Arithmetic mean of all samples
+    Mean,
+    /// Exponential moving average weighted by --alpha
+    Ema,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum AggregateArg {
+    /// Arithmetic mean of the client averages
+    Mean,
+    /// Median of the client averages
+    Median,
+    /// Mean after dropping the top and bottom --trim percent of client averages
+    Trimmed,
+    /// Every sample weighted by its traded volume; the mean when the source reports none
+    Vwap,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum TransportArg {
+    /// Poll the REST spot endpoint every --interval
+    Http,
+    /// Stream the exchange ticker feed, falling back to HTTP polling if it fails
+    Websocket,
+}
+
+impl From<TransportArg> for Transport {
+    fn from(transport: TransportArg) -> Self {
+        match transport {
+            TransportArg::Http => Transport::Http,
+            TransportArg::Websocket => Transport::Websocket,
+        }
+    }
+}
+
+/// Response layouts `--parser` and `--fallback-parser` understand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ParserArg {
+    /// `{"data": {"amount": "..."}}`, as Coinbase serves it
+    Coinbase,
+    /// A top-level `{"price": ...}`, number or string
+    Flat,
+}
+
+impl From<ParserArg> for ResponseShape {
+    fn from(parser: ParserArg) -> Self {
+        match parser {
+            ParserArg::Coinbase => ResponseShape::Coinbase,
+            ParserArg::Flat => ResponseShape::Flat,
+        }
+    }
+}
+
+#[derive(Debug, Parser)]
+#[command(
+    version,
+    about = "Simulates distributed clients polling the Coinbase spot price and aggregates their averages",
+    after_help = EXIT_CODES_HELP
+)]
+pub struct Cli {
+    /// Which mode to run in
+    #[arg(long, value_enum)]
+    pub mode: Mode,
+
+    /// How long each client polls for: seconds, or a duration such as 90s, 2m or 1h30m
+    /// (cache mode) [default: 10]
+    #[arg(long, value_name = "DURATION", value_parser = parse_times)]
+    pub times: Option<u64>,
+
+    /// Take a single reading per client instead of polling for a window (cache mode)
+    #[arg(long, conflicts_with_all = ["times", "warmup"])]
+    pub once: bool,
+
+    /// Stop each client once it has this many averaged samples, even if --times hasn't run out;
+    /// whichever comes first ends it (cache mode)
+    #[arg(long, value_name = "N", conflicts_with = "once", value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub max_samples: Option<usize>,
+
+    /// Seconds at the start of the --times window whose samples are fetched but discarded, which
+    /// keeps cold-connection latency out of the average; must be shorter than --times (cache mode)
+    #[arg(long, value_name = "SECS", default_value_t = 0)]
+    pub warmup: u64,
+
+    /// Price two assets side by side in one run, as "BASE,QUOTE", and report the BASE/QUOTE
+    /// price ratio; replaces --asset (cache mode)
+    #[arg(long, value_name = "BASE,QUOTE", value_parser = parse_compare, conflicts_with = "metrics_addr")]
+    pub compare: Option<(String, String)>,
+
+    /// Crypto asset to price (cache mode)
+    #[arg(long, default_value = DEFAULT_ASSET)]
+    pub asset: String,
+
+    /// Assets `--asset` may name, comma separated (cache mode)
+    #[arg(long, value_delimiter = ',', default_values_t = DEFAULT_ALLOWED_ASSETS.iter().map(|a| a.to_string()))]
+    pub allowed_assets: Vec<String>,
+
+    /// Fiat currencies to price the asset in, comma separated; each gets its own pool of
+    /// clients (cache mode)
+    #[arg(long, value_delimiter = ',', default_value = DEFAULT_CURRENCY, long_help = currency_help())]
+    pub currency: Vec<String>,
+
+    /// Number of concurrent simulated clients (cache mode)
+    #[arg(long, default_value_t = 5, value_parser = RangedU64ValueParser::<usize>::new().range(1..=MAX_CLIENTS))]
+    pub clients: usize,
+
+    /// Stop every client as soon as any one of them has a failed tick, and exit nonzero. What
+    /// was collected until then is still recorded, unless --no-file (cache mode)
+    #[arg(long)]
+    pub fail_fast: bool,
+
+    /// Client reports the aggregator channel holds before clients have to wait for it; waits
+    /// are logged and recorded per client [default: one per client] (cache mode)
+    #[arg(long, value_name = "N", value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub channel_capacity: Option<usize>,
+
+    /// Most requests allowed in flight at once across all clients [default: unlimited] (cache mode)
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_concurrent: Option<u64>,
+
+    /// Most requests per second across all clients; clients wait for a token once it's spent
+    /// [default: unlimited] (cache mode)
+    #[arg(long, value_name = "RPS", value_parser = clap::value_parser!(u32).range(1..))]
+    pub rate: Option<u32>,
+
+    /// Consecutive failed requests, across all clients, that pause everyone (cache mode)
+    #[arg(long, value_name = "N", default_value_t = 5, value_parser = RangedU64ValueParser::<usize>::new().range(1..))]
+    pub breaker_threshold: usize,
+
+    /// How long every client pauses once the breaker trips, in seconds (cache mode)
+    #[arg(long, value_name = "SECS", default_value_t = 5, value_parser = clap::value_parser!(u64).range(1..))]
+    pub breaker_cooldown: u64,
+
+    /// Per-request timeout, in seconds (cache mode)
+    #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(u64).range(1..))]
+    pub timeout: u64,
+
+    /// Hard ceiling on the whole run, in seconds; clients still running are aborted and the
+    /// aggregate is written from what was collected [default: --times plus 30] (cache mode)
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub timeout_total: Option<u64>,
+
+    /// Send every request through this HTTP(S) proxy [default: $HTTPS_PROXY] (cache mode)
+    #[arg(long, value_name = "URL")]
+    pub proxy: Option<String>,
+
+    /// Extra request header as "Name: Value"; repeat for several (cache mode)
+    #[arg(long = "header", value_name = "NAME: VALUE", value_parser = parse_header)]
+    pub headers: Vec<(String, String)>,
+
+    /// Delay between the start of consecutive requests, in milliseconds (cache mode)
+    #[arg(long, default_value_t = 1000, value_parser = clap::value_parser!(u64).range(MIN_INTERVAL_MS..))]
+    pub interval: u64,
+
+    /// Delay each client's first request by a seeded random offset within one --interval.
+    /// Real clients don't start in lockstep, so this spreads requests across each interval
+    /// instead of firing them in bursts (cache mode)
+    #[arg(long)]
+    pub jitter: bool,
+
+    /// Give each client its own --interval and --timeout, scaled by a seeded factor between 0.5x
+    /// and 2x, so clients take uneven sample counts like a real fleet (cache mode)
+    #[arg(long)]
+    pub clients_vary: bool,
+
+    /// Only print the aggregate, don't write result.txt or any other result file. Read and serve
+    /// modes look at the file alone, so they won't see these runs (cache mode)
+    #[arg(long, conflicts_with_all = ["append", "output", "store"])]
+    pub no_file: bool,
+
+    /// Let clients share one fetched price for --cache-ttl instead of each hitting the API,
+    /// like a caching layer in front of it (cache mode)
+    #[arg(long)]
+    pub shared_cache: bool,
+
+    /// How long a --shared-cache price is reused, in milliseconds (cache mode)
+    #[arg(long, value_name = "MS", default_value_t = 1000, requires = "shared_cache", value_parser = clap::value_parser!(u64).range(1..))]
+    pub cache_ttl: u64,
+
+    /// Decimal places for prices on the console and in --format=text files; JSON and CSV
+    /// records keep full precision (cache mode)
+    #[arg(long, value_name = "N", default_value_t = 2, value_parser = RangedU64ValueParser::<usize>::new().range(0..=MAX_OUTPUT_PRECISION))]
+    pub output_precision: usize,
+
+    /// Check that the API answers with a usable price before starting any client; on by default
+    /// (cache mode)
+    #[arg(long, overrides_with = "no_preflight")]
+    pub preflight: bool,
+
+    /// Skip the preflight request, e.g. when the endpoint is known to come up late
+    #[arg(long, overrides_with = "preflight")]
+    pub no_preflight: bool,
+
+    /// Keep earlier runs: result.txt becomes newline-delimited JSON, one record per run (cache mode)
+    #[arg(long)]
+    pub append: bool,
+
+    /// How each client averages its own samples (cache mode)
+    #[arg(long, value_enum, default_value_t = AvgMethod::Mean)]
+    pub avg: AvgMethod,
+
+    /// Smoothing factor for --avg=ema, in (0, 1]; higher favours recent prices
+    #[arg(long, default_value_t = 0.3, value_parser = parse_alpha)]
+    pub alpha: f64,
+
+    /// How the client averages combine into the headline number; the others are still reported
+    /// (cache mode)
+    #[arg(long, value_enum, default_value_t = AggregateArg::Mean)]
+    pub aggregate: AggregateArg,
+
+    /// Percentage of client averages --aggregate=trimmed drops from each end, in [0, 50)
+    #[arg(long, value_name = "PERCENT", default_value_t = 10.0, value_parser = parse_trim)]
+    pub trim: f64,
+
+    /// Average only each client's samples from the last SECS of its window, so long runs track
+    /// the recent price instead of stale early readings [default: the whole window] (cache mode)
+    #[arg(long, value_name = "SECS", conflicts_with = "once", value_parser = clap::value_parser!(u64).range(1..))]
+    pub window: Option<u64>,
+
+    /// Drop client averages more than this many standard deviations from the mean before
+    /// aggregating (cache mode)
+    #[arg(long, value_name = "STD_DEVS", value_parser = parse_positive_f64)]
+    pub reject_outliers: Option<f64>,
+
+    /// Flag a final aggregate below this as suspicious (cache mode)
+    #[arg(long, value_name = "PRICE", value_parser = parse_positive_f64)]
+    pub min_price: Option<f64>,
+
+    /// Flag a final aggregate above this as suspicious (cache mode)
+    #[arg(long, value_name = "PRICE", value_parser = parse_positive_f64)]
+    pub max_price: Option<f64>,
+
+    /// Print an ALERT and exit nonzero when the aggregate moved more than PERCENT from the
+    /// previous run for the same pair in the history (--append or --store=sqlite); no previous
+    /// run, no alert (cache mode)
+    #[arg(long, value_name = "PERCENT", value_parser = parse_positive_f64)]
+    pub alert_change: Option<f64>,
+
+    /// Base URL of the spot price API, e.g. a staging host or local mock; the
+    /// `/v2/prices/{asset}-{currency}/spot` path is appended as usual (cache mode)
+    #[arg(long, value_name = "BASE_URL", default_value = COINBASE_API_BASE, value_parser = parse_endpoint)]
+    pub endpoint: String,
+
+    /// Response layout of --endpoint (cache mode)
+    #[arg(long, value_enum, default_value_t = ParserArg::Coinbase)]
+    pub parser: ParserArg,
+
+    /// How clients get prices (cache mode)
+    #[arg(long, value_enum, default_value_t = TransportArg::Http)]
+    pub transport: TransportArg,
+
+    /// Secondary price URL used when Coinbase fails within a tick; `{asset}` and `{currency}`
+    /// are substituted (cache mode)
+    #[arg(long, value_name = "URL")]
+    pub fallback_url: Option<String>,
+
+    /// Response layout of --fallback-url (cache mode)
+    #[arg(long, value_enum, default_value_t = ParserArg::Coinbase, requires = "fallback_url")]
+    pub fallback_parser: ParserArg,
+
+    /// Ask Coinbase and --fallback-url together on every polled tick and record how far apart
+    /// their prices are, to catch a stale or broken feed. The average still takes Coinbase's
+    /// price whenever it answers (cache mode)
+    #[arg(long, requires = "fallback_url", conflicts_with_all = ["shared_cache", "dry_run"])]
+    pub compare_sources: bool,
+
+    /// Spread between sources, as a percentage of the lowest price, over which a
+    /// --compare-sources tick is flagged (cache mode)
+    #[arg(long, value_name = "PERCENT", default_value_t = 1.0, requires = "compare_sources", value_parser = parse_positive_f64)]
+    pub spread_threshold: f64,
+
+    /// Generate seeded pseudo-random prices instead of calling Coinbase (cache mode)
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Price that --dry-run samples scatter around
+    #[arg(long, default_value_t = 43000.0, value_parser = parse_positive_f64)]
+    pub base_price: f64,
+
+    /// Seed for --dry-run prices, --jitter offsets and the order client reports are aggregated in;
+    /// a random one is chosen and logged when omitted, and reports are then taken as they arrive.
+    /// With --dry-run, the same seed and flags write a byte-identical result file
+    #[arg(long)]
+    pub seed: Option<u64>,
+
+    /// Result file format; read mode uses it to pick the default file and detects the format itself
+    #[arg(long, value_enum, default_value_t = OutputFormat::Json)]
+    pub format: OutputFormat,
+
+    /// Where to write the result; missing parent directories are created (cache mode)
+    /// [default: result.txt, or result.csv/result.md with --format=csv/markdown]
+    #[arg(long)]
+    pub output: Option<PathBuf>,
+
+    /// Result file to read (read, serve and stats modes) [default: result.txt, or result.csv/result.md with --format=csv/markdown]
+    #[arg(long)]
+    pub input: Option<PathBuf>,
+
+    /// Address to listen on (serve mode)
+    #[arg(long, default_value = DEFAULT_ADDR)]
+    pub addr: SocketAddr,
+
+    /// Serve Prometheus metrics at http://ADDR/metrics while the run is in progress (cache mode)
+    #[arg(long, value_name = "ADDR")]
+    pub metrics_addr: Option<SocketAddr>,
+
+    /// Where runs are persisted (cache mode) and read back from (read mode)
+    #[arg(long, value_enum, default_value_t = Store::File)]
+    pub store: Store,
+
+    /// SQLite database used with --store=sqlite; created on first use
+    #[arg(long, value_name = "PATH", default_value = DEFAULT_DB)]
+    pub db: PathBuf,
+
+    /// Show the change between the first and last recorded runs (read mode)
+    #[arg(long)]
+    pub trend: bool,
+
+    /// Keep watching the file and print runs appended to it, like `tail -f` (read mode)
+    #[arg(long)]
+    pub follow: bool,
+
+    /// TOML file of flag values, e.g. `clients = 10`; flags given on the command line win
+    #[arg(long, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// Log filter, e.g. `info`, `debug` or `simulated_distributed_client=trace`
+    #[arg(long, default_value = "info")]
+    pub log_level: String,
+
+    /// Print only the final aggregate line and log only warnings and errors, overriding --log-level
+    #[arg(long, short)]
+    pub quiet: bool,
+
+    /// Print one JSON object per event (client finished, aggregate computed) to stdout instead
+    /// of the human-readable lines (cache mode); stats mode prints its report as one JSON array
+    #[arg(long)]
+    pub json: bool,
+
+    /// Log every price as it arrives, with its client and time; with --json each one is a
+    /// `sample` event line instead
+    #[arg(long, short, conflicts_with = "quiet")]
+    pub verbose: bool,
+}
+
+impl Cli {
+    pub fn console(&self) -> Console {
+        if self.json {
+            Console::Json
+        } else if self.quiet {
+            Console::Quiet
+        } else if self.format == OutputFormat::Markdown {
+            Console::Markdown
+        } else {
+            Console::Human
+        }
+    }
+
+    /// `--proxy`, else the conventional `HTTPS_PROXY`/`https_proxy` environment variable.
+    pub fn proxy(&self) -> Option<String> {
+        let (proxy, origin) = match &self.proxy {
+            Some(proxy) => (proxy.clone(), "--proxy"),
+            None => {
+                let proxy = std::env::var("HTTPS_PROXY")
+                    .or_else(|_| std::env::var("https_proxy"))
+                    .ok()
+                    .filter(|proxy| !proxy.is_empty())?;
+                (proxy, "HTTPS_PROXY")
+            }
+        };
+
+        info!("Using proxy {} (from {})", redact_proxy(&proxy), origin);
+        Some(proxy)
+    }
+
+    /// `--dry-run --seed=N` replays exactly, so its record leaves out everything wall-clock.
+    pub fn reproducible(&self) -> bool {
+        self.dry_run && self.seed.is_some()
+    }
+
+    /// `--preflight` is the default; only `--no-preflight` turns it off. Dry runs make no
+    /// requests and the WebSocket feed is not the REST endpoint, so neither is checked.
+    pub fn preflight(&self) -> bool {
+        !self.no_preflight && !self.dry_run && self.transport == TransportArg::Http
+    }
+
+    /// The `--spread-threshold` to flag ticks by, when `--compare-sources` is on.
+    pub fn compare_sources(&self) -> Option<f64> {
+        self.compare_sources.then_some(self.spread_threshold)
+    }
+
+    /// `--currency` validated, in the order given and without repeats.
+    pub fn currencies(&self) -> Vec<String> {
+        let mut currencies: Vec<String> = Vec::new();
+        for currency in self.currency.iter().map(|code| validate_currency(code.trim())) {
+            if !currencies.contains(&currency) {
+                currencies.push(currency);
+            }
+        }
+        currencies
+    }
+
+    /// The history database, when `--store=sqlite` selects it.
+    pub fn db_path(&self) -> Option<PathBuf> {
+        (self.store == Store::Sqlite).then(|| self.db.clone())
+    }
+
+    pub fn log_filter(&self) -> String {
+        if self.quiet {
+            "warn".to_string()
+        } else if self.verbose && !self.json {
+            format!("{},{}=debug", self.log_level, SAMPLE_LOG_TARGET)
+        } else {
+            self.log_level.clone()
+        }
+    }
+
+    pub fn sample_output(&self) -> SampleOutput {
+        match (self.verbose, self.json) {
+            (false, _) => SampleOutput::Off,
+            (true, false) => SampleOutput::Log,
+            (true, true) => SampleOutput::Json,
+        }
+    }
+
+    pub fn averaging(&self) -> Averaging {
+        match self.avg {
+            AvgMethod::Mean => Averaging::Mean,
+            AvgMethod::Ema => Averaging::Ema { alpha: self.alpha },
+        }
+    }
+
+    pub fn aggregate_method(&self) -> AggregateMethod {
+        match self.aggregate {
+            AggregateArg::Mean => AggregateMethod::Mean,
+            AggregateArg::Median => AggregateMethod::Median,
+            AggregateArg::Trimmed => AggregateMethod::Trimmed { percent: self.trim },
+            AggregateArg::Vwap => AggregateMethod::Vwap,
+        }
+    }
+
+    /// `--timeout-total`, defaulting to the polling window plus a grace period.
+    pub fn timeout_total(&self, times: u64) -> Duration {
+        Duration::from_secs(self.timeout_total.unwrap_or(times + TOTAL_TIMEOUT_GRACE_SECS))
+    }
+
+    /// The polling window, falling back to the default with a hint when `--times` was omitted.
+    /// `--once` ignores the window, so no hint is given then.
+    pub fn times_or_default(&self) -> u64 {
+        if self.once {
+            return DEFAULT_TIMES_SECS;
+        }
+        self.times.unwrap_or_else(|| {
+            info!("No --times=<seconds> given, defaulting to {} seconds.", DEFAULT_TIMES_SECS);
+            DEFAULT_TIMES_SECS
+        })
+    }
+}
+
+fn parse_times(value: &str) -> Result<u64, String> {
+    match parse_duration_secs(value)? {
+        0 => Err("the polling window must be at least 1 second".to_string()),
+        secs if secs > MAX_TIMES_SECS => Err(format!(
+            "{} seconds exceeds the maximum of {} seconds",
+            secs, MAX_TIMES_SECS
+        )),
+        secs => Ok(secs),
+    }
+}
+
+/// Whole seconds in a bare number of seconds or a `humantime` duration such as `90s`, `2m`,
+/// `1h30m` or `1h 30min`. Fractions of a second are refused rather than rounded.
+pub fn parse_duration_secs(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Ok(secs);
+    }
+
+    let invalid = |reason: String| {
+        format!(
+            "'{}' is not a number of seconds or a duration like 90s, 2m or 1h30m: {}",
+            value, reason
+        )
+    };
+    let duration = humantime::parse_duration(value).map_err(|e| invalid(e.to_string()))?;
+    if duration.subsec_nanos() != 0 {
+        return Err(invalid("it is not a whole number of seconds".to_string()));
+    }
+
+    Ok(duration.as_secs())
+}
+
+fn parse_endpoint(value: &str) -> Result<String, String> {
+    let url = reqwest::Url::parse(value).map_err(|e| format!("'{}' is not a valid URL: {}", value, e))?;
+    if !matches!(url.scheme(), "http" | "https") || url.host().is_none() {
+        return Err(format!("'{}' must be an http:// or https:// URL with a host", value));
+    }
+
+    Ok(value.trim_end_matches('/').to_string())
+}
+
+fn parse_compare(value: &str) -> Result<(String, String), String> {
+    match value.split(',').map(str::trim).collect::<Vec<_>>()[..] {
+        [base, quote] if !base.is_empty() && !quote.is_empty() && !base.eq_ignore_ascii_case(quote) => {
+            Ok((base.to_string(), quote.to_string()))
+        }
+        _ => Err(format!("'{}' must be two different assets, e.g. BTC,ETH", value)),
+    }
+}
+
+fn parse_header(value: &str) -> Result<(String, String), String> {
+    let (name, header_value) = value
+        .split_once(':')
+        .ok_or_else(|| format!("'{}' is not of the form \"Name: Value\"", value))?;
+    let (name, header_value) = (name.trim(), header_value.trim());
+
+    reqwest::header::HeaderName::from_bytes(name.as_bytes())
+        .map_err(|_| format!("'{}' is not a valid header name", name))?;
+    reqwest::header::HeaderValue::from_str(header_value)
+        .map_err(|_| format!("the value for header '{}' contains invalid characters", name))?;
+
+    Ok((name.to_string(), header_value.to_string()))
+}
+
+fn parse_alpha(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(alpha) if alpha > 0.0 && alpha <= 1.0 => Ok(alpha),
+        _ => Err(format!("'{}' must be a number in (0, 1]", value)),
+    }
+}
+
+fn parse_trim(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(percent) if (0.0..50.0).contains(&percent) => Ok(percent),
+        _ => Err(format!("'{}' must be a percentage in [0, 50)", value)),
+    }
+}
+
+fn parse_positive_f64(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(v) if v.is_finite() && v > 0.0 => Ok(v),
+        _ => Err(format!("'{}' is not a positive number", value)),
+    }
+}
+
+fn currency_help() -> String {
+    format!(
+        "Fiat currencies to price the asset in, comma separated; with more than one, each gets its own \
+         pool of --clients and its own aggregate (cache mode). Supported: {}. Unknown codes fall back to {}.",
+        SUPPORTED_CURRENCIES.join(", "),
+        DEFAULT_CURRENCY
+    )
+}
+
+pub fn validate_currency(code: &str) -> String {
+    let code = code.to_ascii_uppercase();
+
+    if SUPPORTED_CURRENCIES.contains(&code.as_str()) {
+        code
+    } else {
+        warn!("Unknown currency '{}', falling back to {}.", code, DEFAULT_CURRENCY);
+        DEFAULT_CURRENCY.to_string()
+    }
+}
+
+pub fn validate_asset(asset: &str, allowed_assets: &[String]) -> Result<String, ClientError> {
+    let asset = asset.to_ascii_uppercase();
+
+    if allowed_assets.iter().any(|allowed| allowed.eq_ignore_ascii_case(&asset)) {
+        Ok(asset)
+    } else {
+        Err(ClientError::Config(format!(
+            "unsupported asset '{}', expected one of: {}",
+            asset,
+            allowed_assets.join(", ")
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Cli, clap::Error> {
+        Cli::try_parse_from(std::iter::once("simulated_distributed_client").chain(args.iter().copied()))
+    }
+
+    #[test]
+    fn interval_is_read_in_milliseconds() {
+        let cli = parse(&["--mode=cache", "--interval=500"]).expect("valid flags");
+
+        assert_eq!(cli.interval, 500);
+    }
+
+    #[test]
+    fn interval_below_the_minimum_is_rejected() {
+        let err = parse(&["--mode=cache", "--interval=50"]).unwrap_err();
+
+        assert_eq!(err.kind(), clap::error::ErrorKind::ValueValidation);
+    }
+
+    #[test]
+    fn cache_mode_needs_no_other_flags() {
+        let cli = parse(&["--mode=cache"]).expect("--mode alone is enough");
+
+        assert_eq!(cli.mode, Mode::Cache);
+        assert_eq!(cli.times, None);
+        assert_eq!(cli.times_or_default(), DEFAULT_TIMES_SECS);
+    }
+
+    #[test]
+    fn times_within_the_limits_is_accepted() {
+        assert_eq!(parse(&["--mode=cache", "--times=1"]).unwrap().times, Some(1));
+        assert_eq!(parse(&["--mode=cache", "--times=3600"]).unwrap().times, Some(MAX_TIMES_SECS));
+    }
+
+    #[test]
+    fn times_outside_the_limits_is_rejected_with_the_reason() {
+        for (times, reason) in [
+            ("abc", "'abc' is not a number of seconds"),
+            ("0", "at least 1 second"),
+            ("3601", "exceeds the maximum of 3600 seconds"),
+        ] {
+            let err = parse(&["--mode=cache", &format!("--times={}", times)]).unwrap_err();
+
+            assert_eq!(err.kind(), clap::error::ErrorKind::ValueValidation, "--times={}", times);
+            assert!(err.to_string().contains(reason), "--times={}: {}", times, err);
+        }
+    }
+
+    /// A numeric flag, the flags it needs alongside, a value it takes and values it refuses,
+    /// each with part of the reason clap gives.
+    struct NumericFlag {
+        flag: &'static str,
+        needs: &'static [&'static str],
+        good: &'static str,
+        bad: &'static [(&'static str, &'static str)],
+    }
+
+    const NUMERIC_FLAGS: &[NumericFlag] = &[
+        NumericFlag {
+            flag: "max-samples",
+            needs: &[],
+            good: "5",
+            bad: &[("0", "0 is not in 1.."), ("x", "invalid digit")],
+        },
+        NumericFlag {
+            flag: "warmup",
+            needs: &[],
+            good: "2",
+            bad: &[("-1", "invalid digit"), ("2.5", "invalid digit")],
+        },
+        NumericFlag {
+            flag: "clients",
+            needs: &[],
+            good: "100",
+            bad: &[("0", "0 is not in 1..=100"), ("101", "101 is not in 1..=100")],
+        },
+        NumericFlag {
+            flag: "channel-capacity",
+            needs: &[],
+            good: "1",
+            bad: &[("0", "0 is not in 1..")],
+        },
+        NumericFlag {
+            flag: "max-concurrent",
+            needs: &[],
+            good: "3",
+            bad: &[("0", "0 is not in 1..")],
+        },
+        NumericFlag {
+            flag: "rate",
+            needs: &[],
+            good: "10",
+            bad: &[("0", "0 is not in 1..")],
+        },
+        NumericFlag {
+            flag: "breaker-threshold",
+            needs: &[],
+            good: "1",
+            bad: &[("0", "0 is not in 1..")],
+        },
+        NumericFlag {
+            flag: "breaker-cooldown",
+            needs: &[],
+            good: "1",
+            bad: &[("0", "0 is not in 1..")],
+        },
+        NumericFlag {
+            flag: "timeout",
+            needs: &[],
+            good: "1",
+            bad: &[("0", "0 is not in 1.."), ("5s", "invalid digit")],
+        },
+        NumericFlag {
+            flag: "timeout-total",
+            needs: &[],
+            good: "60",
+            bad: &[("0", "0 is not in 1..")],
+        },
+        NumericFlag {
+            flag: "interval",
+            needs: &[],
+            good: "100",
+            bad: &[("99", "99 is not in 100..")],
+        },
+        NumericFlag {
+            flag: "cache-ttl",
+            needs: &["--shared-cache"],
+            good: "1",
+            bad: &[("0", "0 is not in 1..")],
+        },
+        NumericFlag {
+            flag: "output-precision",
+            needs: &[],
+            good: "12",
+            bad: &[("13", "13 is not in 0..=12")],
+        },
+        NumericFlag {
+            flag: "alpha",
+            needs: &[],
+            good: "1",
+            bad: &[("0", "must be a number in (0, 1]"), ("1.5", "must be a number in (0, 1]")],
+        },
+        NumericFlag {
+            flag: "trim",
+            needs: &[],
+            good: "0",
+            bad: &[("50", "must be a percentage in [0, 50)"), ("-1", "must be a percentage in [0, 50)")],
+        },
+        NumericFlag {
+            flag: "window",
+            needs: &[],
+            good: "5",
+            bad: &[("0", "0 is not in 1..")],
+        },
+        NumericFlag {
+            flag: "reject-outliers",
+            needs: &[],
+            good: "2.5",
+            bad: &[("0", "is not a positive number"), ("inf", "is not a positive number")],
+        },
+        NumericFlag {
+            flag: "min-price",
+            needs: &[],
+            good: "0.01",
+            bad: &[("0", "is not a positive number")],
+        },
+        NumericFlag {
+            flag: "max-price",
+            needs: &[],
+            good: "100000",
+            bad: &[("NaN", "is not a positive number")],
+        },
+        NumericFlag {
+            flag: "alert-change",
+            needs: &[],
+            good: "5",
+            bad: &[("abc", "is not a positive number")],
+        },
+        NumericFlag {
+            flag: "spread-threshold",
+            needs: &["--fallback-url=http://127.0.0.1:9", "--compare-sources"],
+            good: "0.5",
+            bad: &[("0", "is not a positive number")],
+        },
+        NumericFlag {
+            flag: "base-price",
+            needs: &[],
+            good: "43000",
+            bad: &[("-5", "is not a positive number"), ("0", "is not a positive number")],
+        },
+        NumericFlag {
+            flag: "seed",
+            needs: &[],
+            good: "42",
+            bad: &[("-1", "invalid digit"), ("x", "invalid digit")],
+        },
+    ];
+
+    #[test]
+    fn every_numeric_flag_takes_a_valid_value() {
+        for NumericFlag { flag, needs, good, .. } in NUMERIC_FLAGS {
+            let arg = format!("--{}={}", flag, good);
+            let mut args = vec!["--mode=cache", arg.as_str()];
+            args.extend_from_slice(needs);
+
+            if let Err(e) = parse(&args) {
+                panic!("{} was refused: {}", arg, e);
+            }
+        }
+    }
+
+    #[test]
+    fn every_numeric_flag_refuses_a_bad_value_by_name() {
+        for NumericFlag { flag, needs, bad, .. } in NUMERIC_FLAGS {
+            for (value, reason) in *bad {
+                let arg = format!("--{}={}", flag, value);
+                let mut args = vec!["--mode=cache", arg.as_str()];
+                args.extend_from_slice(needs);
+
+                let message = match parse(&args) {
+                    Ok(_) => panic!("{} was accepted", arg),
+                    Err(e) => e.to_string(),
+                };
+                assert!(message.contains(&format!("invalid value '{}' for '--{} <", value, flag)), "{}: {}", arg, message);
+                assert!(message.contains(reason), "{}: {}", arg, message);
+            }
+        }
+    }
+
+    #[test]
+    fn durations_are_read_as_whole_seconds() {
+        for (value, secs) in [
+            ("45", 45),
+            ("90s", 90),
+            ("2m", 120),
+            ("1h", 3600),
+            ("1h30m", 5400),
+            ("1m 30s", 90),
+            ("2minutes", 120),
+            ("1d", 86400),
+            ("1.5m", 90),
+        ] {
+            assert_eq!(parse_duration_secs(value), Ok(secs), "{}", value);
+        }
+    }
+
+    #[test]
+    fn malformed_durations_are_rejected() {
+        for value in ["", "abc", "90x", "m", "-1m", "1500ms", "99999999999999999999h"] {
+            let err = parse_duration_secs(value).unwrap_err();
+            assert!(err.contains("is not a number of seconds or a duration"), "{}: {}", value, err);
+        }
+    }
+
+    #[test]
+    fn times_takes_a_duration() {
+        assert_eq!(parse(&["--mode=cache", "--times=2m"]).unwrap().times, Some(120));
+        let err = parse(&["--mode=cache", "--times=2h"]).unwrap_err();
+        assert!(err.to_string().contains("7200 seconds exceeds the maximum"), "{}", err);
+    }
+}
@@ -0,0 +1,1314 @@
+use crate::aggregator::{ClientMessage, ClientReport, Outcome, RequestStats, Sample, SourceAverage, SourceSpread, StopReason};
+use crate::backpressure::Backpressure;
+use crate::breaker::CircuitBreaker;
+use crate::cache::PriceCache;
+use crate::cli::MIN_INTERVAL_MS;
+use crate::error::ClientError;
+use crate::metrics::Metrics;
+use crate::output::{print_event, Event};
+use crate::source::{build_sources, request_error_kind, ApiCredentials, CoinbaseSource, FetchError, PriceSource, ResponseShape};
+use crate::websocket::stream_prices;
+use chrono::Utc;
+use futures_util::future::join_all;
+use governor::{DefaultDirectRateLimiter, Quota, RateLimiter};
+use rand::rngs::StdRng;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::num::NonZeroU32;
+use std::sync::{Arc, OnceLock};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
+
+pub const COINBASE_API_BASE: &str = "https://api.coinbase.com";
+pub const COINBASE_WS_FEED: &str = "wss://ws-feed.exchange.coinbase.com";
+
+/// Identifies our traffic to the API instead of reqwest's generic default.
+pub const USER_AGENT: &str = concat!("Rust-SimulatedDistributedClient/", env!("CARGO_PKG_VERSION"));
+
+/// Delays before each retry of a failed fetch within a single tick.
+const RETRY_BACKOFF_MS: &[u64] = &[250, 500, 1000];
+
+/// Range of the factor `--clients-vary` scales each client's interval and timeout by.
+const VARY_FACTOR_MIN: f64 = 0.5;
+const VARY_FACTOR_MAX: f64 = 2.0;
+
+/// Keeps `--clients-vary` draws independent of the `--jitter` offsets made from the same seed.
+const VARY_SEED_SALT: u64 = 0x5eed_0fc1_1e47;
+
+/// Longest `Retry-After` honoured before a retry; the window deadline still cuts it short.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// Settings shared by every simulated client in a cache run.
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// Scheme and host the price API is served from, without a trailing slash.
+    pub base_url: String,
+    pub times: u64,
+    /// Make a single fetch (with its retries) instead of polling for `times` seconds.
+    pub once: bool,
+    /// End each client after this many averaged samples, if the window hasn't closed first.
+    pub max_samples: Option<usize>,
+    pub asset: String,
+    pub currency: String,
+    pub timeout: Duration,
+    /// Ceiling on the whole run, after which clients still going are aborted.
+    pub total_timeout: Duration,
+    /// Every request goes through this proxy, from `--proxy` or `HTTPS_PROXY`.
+    pub proxy: Option<String>,
+    /// Signs Coinbase requests when `COINBASE_API_KEY` is set; `None` stays anonymous.
+    pub credentials: Option<ApiCredentials>,
+    /// Extra `--header` name/value pairs sent with every request, already validated.
+    pub headers: Vec<(String, String)>,
+    pub interval: Duration,
+    pub averaging: Averaging,
+    /// Average only the samples from this trailing span; `None` averages the whole window.
+    pub window: Option<Duration>,
+    pub transport: Transport,
+    /// Ticker feed used by `Transport::Websocket`.
+    pub ws_url: String,
+    /// How the primary endpoint's response bodies are read, from `--parser`.
+    pub parser: ResponseShape,
+    /// Secondary provider URL template tried when Coinbase keeps failing within a tick.
+    pub fallback_url: Option<String>,
+    /// How the fallback's response bodies are read.
+    pub fallback_parser: ResponseShape,
+    /// With `--compare-sources`, every source is asked on every tick, and a tick whose answers
+    /// spread wider than this percentage is flagged.
+    pub compare_sources: Option<f64>,
+    /// Cap on requests in flight at once across all clients; `None` leaves them unlimited.
+    pub max_concurrent: Option<usize>,
+    /// Cap on requests started per second across all clients; `None` leaves the rate unlimited.
+    pub rate: Option<NonZeroU32>,
+    /// With `--shared-cache`, how long one fetched price serves every client's ticks.
+    pub cache_ttl: Option<Duration>,
+    /// Leading part of the window whose samples are fetched but not averaged.
+    pub warmup: Duration,
+    /// Consecutive failed ticks, across all clients, that open the circuit breaker.
+    pub breaker_threshold: usize,
+    /// How long an open circuit breaker holds every client off.
+    pub breaker_cooldown: Duration,
+    /// Seed for each client's random start offset under `--jitter`; `None` starts everyone at once.
+    pub jitter_seed: Option<u64>,
+    /// Seed for each client's own interval and timeout under `--clients-vary`; `None` keeps them uniform.
+    pub vary_seed: Option<u64>,
+    /// When set, prices are generated locally instead of fetched (`--dry-run`).
+    pub synthetic: Option<SyntheticConfig>,
+    /// With `--fail-fast`, tripped by the first failed tick to stop every client.
+    pub fail_fast: Option<FailFast>,
+    /// Whether `--verbose` reports every price as it is recorded.
+    pub samples: SampleOutput,
+    /// Decimal places for the average in this client's log line.
+    pub precision: usize,
+}
+
+/// `--fail-fast`: the first client with a failed tick records why and cancels every client,
+/// in every `--compare` or `--currency` pool, through the token each pool's shutdown derives from.
+#[derive(Debug, Clone, Default)]
+pub struct FailFast {
+    token: CancellationToken,
+    reason: Arc<OnceLock<String>>,
+}
+
+impl FailFast {
+    /// A pool's shutdown token, cancelled along with every other pool's when this trips.
+    pub fn shutdown_token(&self) -> CancellationToken {
+        self.token.child_token()
+    }
+
+    /// Stops the run because of `error` on `client_id`; only the first caller's reason is kept.
+    fn trip(&self, client_id: usize, error: &str) -> String {
+        let reason = format!("client {} failed: {}", client_id, error);
+        if self.reason.set(reason.clone()).is_ok() {
+            error!("--fail-fast: stopping every client, {}", reason);
+        }
+        self.token.cancel();
+        reason
+    }
+
+    /// Why the run was stopped, once it has been.
+    pub fn reason(&self) -> Option<&str> {
+        self.reason.get().map(String::as_str)
+    }
+}
+
+/// Log target of the per-sample lines, so `--verbose` can enable them without the rest of `debug`.
+pub const SAMPLE_LOG_TARGET: &str = "samples";
+
+/// Where `--verbose` sends each individual price.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SampleOutput {
+    #[default]
+    Off,
+    /// A `debug` line under `SAMPLE_LOG_TARGET`.
+    Log,
+    /// A `sample` event on stdout, alongside the other `--json` events.
+    Json,
+}
+
+/// How a client folds its samples into the single average it reports.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(tag = "method", rename_all = "lowercase")]
+pub enum Averaging {
+    /// Arithmetic mean of every sample.
+    #[default]
+    Mean,
+    /// Exponential moving average; higher `alpha` weights recent samples more.
+    Ema { alpha: f64 },
+}
+
+/// How clients obtain prices.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Transport {
+    /// Poll the REST spot endpoint every interval.
+    #[default]
+    Http,
+    /// Accumulate prices pushed by the exchange ticker feed, polling only if it fails.
+    Websocket,
+}
+
+/// Running state for one client's `Averaging`.
+struct RunningAverage {
+    averaging: Averaging,
+    sum: f64,
+    ema: Option<f64>,
+    count: usize,
+    /// Every sample, kept for the run-wide percentiles and VWAP.
+    prices: Vec<Sample>,
+    /// With `--window`, the timestamped samples still inside it, oldest first.
+    window: Option<Duration>,
+    recent: VecDeque<(Instant, f64)>,
+}
+
+impl RunningAverage {
+    fn new(averaging: Averaging, window: Option<Duration>) -> Self {
+        RunningAverage {
+            averaging,
+            sum: 0.0,
+            ema: None,
+            count: 0,
+            prices: Vec::new(),
+            window,
+            recent: VecDeque::new(),
+        }
+    }
+
+    fn add(&mut self, sample: Sample) {
+        let price = sample.price;
+        self.sum += price;
+        self.count += 1;
+        self.prices.push(sample);
+        if let Averaging::Ema { alpha } = self.averaging {
+            self.ema = Some(match self.ema {
+                Some(previous) => alpha * price + (1.0 - alpha) * previous,
+                None => price,
+            });
+        }
+        if self.window.is_some() {
+            self.recent.push_back((Instant::now(), price));
+            self.expire();
+        }
+    }
+
+    /// Drops samples older than the window, always keeping the latest so a window shorter than
+    /// the interval still has a price to report.
+    fn expire(&mut self) {
+        let Some(window) = self.window else {
+            return;
+        };
+        while self.recent.len() > 1 && self.recent.front().is_some_and(|(at, _)| at.elapsed() > window) {
+            self.recent.pop_front();
+        }
+    }
+
+    fn value(&mut self) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+        if self.window.is_some() {
+            self.expire();
+            return self.windowed_value();
+        }
+
+        match self.averaging {
+            Averaging::Mean => Some(self.sum / self.count as f64),
+            Averaging::Ema { .. } => self.ema,
+        }
+    }
+
+    fn windowed_value(&self) -> Option<f64> {
+        let mut prices = self.recent.iter().map(|(_, price)| *price);
+        match self.averaging {
+            Averaging::Mean => Some(prices.clone().sum::<f64>() / self.recent.len() as f64),
+            Averaging::Ema { alpha } => {
+                let first = prices.next()?;
+                Some(prices.fold(first, |previous, price| alpha * price + (1.0 - alpha) * previous))
+            }
+        }
+    }
+}
+
+/// What `--compare-sources` saw over one client's window.
+struct SpreadTally {
+    threshold_percent: f64,
+    /// Sum and count of each source's prices.
+    sums: BTreeMap<String, (f64, usize)>,
+    max_spread_percent: f64,
+    compared_ticks: usize,
+    disagreements: usize,
+}
+
+impl SpreadTally {
+    fn new(threshold_percent: f64) -> Self {
+        SpreadTally {
+            threshold_percent,
+            sums: BTreeMap::new(),
+            max_spread_percent: 0.0,
+            compared_ticks: 0,
+            disagreements: 0,
+        }
+    }
+
+    /// Counts one tick's answers, warning when they are further apart than the threshold.
+    fn record(&mut self, readings: &[(&str, f64)]) {
+        for &(source, price) in readings {
+            let (sum, count) = self.sums.entry(source.to_string()).or_default();
+            *sum += price;
+            *count += 1;
+        }
+
+        let Some(spread) = spread_percent(readings.iter().map(|(_, price)| *price)) else {
+            return;
+        };
+        self.compared_ticks += 1;
+        self.max_spread_percent = self.max_spread_percent.max(spread);
+        if spread > self.threshold_percent {
+            self.disagreements += 1;
+            let answers: Vec<String> = readings
+                .iter()
+                .map(|(source, price)| format!("{}={}", source, price))
+                .collect();
+            warn!(
+                "Sources disagree by {:.2}% ({}), over the {}% threshold",
+                spread,
+                answers.join(", "),
+                self.threshold_percent
+            );
+        }
+    }
+
+    fn into_spread(self) -> SourceSpread {
+        SourceSpread {
+            by_source: self
+                .sums
+                .into_iter()
+                .map(|(source, (sum, samples))| {
+                    let mean = sum / samples as f64;
+                    (source, SourceAverage { mean, samples })
+                })
+                .collect(),
+            max_spread_percent: self.max_spread_percent,
+            compared_ticks: self.compared_ticks,
+            disagreements: self.disagreements,
+            threshold_percent: self.threshold_percent,
+        }
+    }
+}
+
+/// Gap between the highest and lowest price as a percentage of the lowest; `None` with fewer
+/// than two prices or a non-positive one, where there is nothing meaningful to compare.
+fn spread_percent(prices: impl Iterator<Item = f64>) -> Option<f64> {
+    let (count, low, high) = prices.fold((0, f64::INFINITY, f64::NEG_INFINITY), |(count, low, high), price| {
+        (count + 1, low.min(price), high.max(price))
+    });
+
+    (count >= 2 && low > 0.0).then(|| (high - low) / low * 100.0)
+}
+
+/// Everything one client accumulates over its window, whichever transport supplied it.
+pub struct Tally {
+    client_id: usize,
+    samples: SampleOutput,
+    running: RunningAverage,
+    requests: RequestStats,
+    samples_by_source: BTreeMap<String, usize>,
+    warmup_discarded: usize,
+}
+
+impl Tally {
+    fn new(config: &ClientConfig, client_id: usize) -> Self {
+        Tally {
+            client_id,
+            samples: config.samples,
+            running: RunningAverage::new(config.averaging, config.window),
+            requests: RequestStats::default(),
+            samples_by_source: BTreeMap::new(),
+            warmup_discarded: 0,
+        }
+    }
+
+    /// Counts one fetch or pushed update, averaging its price unless it arrived during warmup.
+    pub fn record(&mut self, outcome: Outcome, sample: Option<(Sample, &str)>, in_warmup: bool, metrics: &Metrics) {
+        self.requests.record(outcome);
+        metrics.record_request(outcome);
+
+        if let Some((sample, source)) = sample {
+            self.report_sample(sample, source, in_warmup);
+            if in_warmup {
+                self.warmup_discarded += 1;
+            } else {
+                self.running.add(sample);
+                metrics.observe_sample(sample.price);
+                *self.samples_by_source.entry(source.to_string()).or_default() += 1;
+            }
+        }
+    }
+
+    fn reached_max_samples(&self, config: &ClientConfig) -> bool {
+        config.max_samples.is_some_and(|max| self.running.count >= max)
+    }
+
+    fn report_sample(&self, sample: Sample, source: &str, in_warmup: bool) {
+        match self.samples {
+            SampleOutput::Off => {}
+            SampleOutput::Log => debug!(
+                target: SAMPLE_LOG_TARGET,
+                "Client {}: {}{} from {} at {}{}",
+                self.client_id,
+                sample.price,
+                sample.volume.map(|volume| format!(" (volume {})", volume)).unwrap_or_default(),
+                source,
+                Utc::now().to_rfc3339(),
+                if in_warmup { " (warmup)" } else { "" }
+            ),
+            SampleOutput::Json => print_event(&Event::Sample {
+                client_id: self.client_id,
+                price: sample.price,
+                volume: sample.volume,
+                source,
+                at: Utc::now(),
+                warmup: in_warmup,
+            }),
+        }
+    }
+
+    /// `--once` wants exactly one reading; otherwise the `--times` window decides, unless
+    /// `--max-samples` is reached first.
+    pub fn window_open(&self, config: &ClientConfig, start_time: Instant) -> bool {
+        if self.reached_max_samples(config) {
+            false
+        } else if config.once {
+            self.requests.ok + self.requests.failures() == 0
+        } else {
+            start_time.elapsed().as_secs() < config.times
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SyntheticConfig {
+    pub base_price: f64,
+    pub seed: u64,
+}
+
+/// Relative spread of generated prices around `base_price`.
+const SYNTHETIC_SPREAD: f64 = 0.005;
+
+/// Seeded price generator, one per client so runs with the same seed repeat exactly.
+struct SyntheticPrices {
+    rng: StdRng,
+    base_price: f64,
+}
+
+impl SyntheticPrices {
+    fn new(config: SyntheticConfig, client_id: usize) -> Self {
+        SyntheticPrices {
+            rng: StdRng::seed_from_u64(config.seed.wrapping_add(client_id as u64)),
+            base_price: config.base_price,
+        }
+    }
+
+    fn next_price(&mut self) -> f64 {
+        self.base_price * (1.0 + self.rng.gen_range(-SYNTHETIC_SPREAD..=SYNTHETIC_SPREAD))
+    }
+}
+
+impl ClientConfig {
+    pub fn spot_url(&self) -> String {
+        format!("{}/v2/prices/{}-{}/spot", self.base_url, self.asset, self.currency)
+    }
+
+    /// When the `--times` window closes for a run that started at `start_time`.
+    pub fn deadline(&self, start_time: Instant) -> Instant {
+        start_time + Duration::from_secs(self.times)
+    }
+
+    /// How long `client_id` waits before its first tick: somewhere in `0..interval`, fixed by the seed.
+    fn start_offset(&self, client_id: usize) -> Option<Duration> {
+        let seed = self.jitter_seed?;
+        let interval_ms = self.interval.as_millis() as u64;
+        let mut rng = StdRng::seed_from_u64(seed.wrapping_add(client_id as u64));
+
+        Some(Duration::from_millis(rng.gen_range(0..interval_ms.max(1))))
+    }
+
+    /// This client's own copy of the settings: under `--clients-vary` its interval and timeout
+    /// are scaled by seeded factors, never below the minimum interval or one second.
+    fn for_client(&self, client_id: usize) -> ClientConfig {
+        let mut config = self.clone();
+        let Some(seed) = self.vary_seed else {
+            return config;
+        };
+
+        let mut rng = StdRng::seed_from_u64((seed ^ VARY_SEED_SALT).wrapping_add(client_id as u64));
+        let min_interval = Duration::from_millis(MIN_INTERVAL_MS);
+        config.interval = self
+            .interval
+            .mul_f64(rng.gen_range(VARY_FACTOR_MIN..=VARY_FACTOR_MAX))
+            .max(min_interval);
+        config.timeout = self
+            .timeout
+            .mul_f64(rng.gen_range(VARY_FACTOR_MIN..=VARY_FACTOR_MAX))
+            .max(Duration::from_secs(1));
+        info!(
+            "Client {}: interval {}ms, timeout {:.1}s",
+            client_id,
+            config.interval.as_millis(),
+            config.timeout.as_secs_f64()
+        );
+        config
+    }
+}
+
+/// Run-wide state every client holds a handle to.
+#[derive(Debug, Clone)]
+pub struct SharedState {
+    /// Caps requests in flight across clients under `--max-concurrent`.
+    pub limiter: Option<Arc<Semaphore>>,
+    /// Token bucket refilled at `--rate` requests per second, shared by every client.
+    pub rate_limiter: Option<Arc<DefaultDirectRateLimiter>>,
+    pub breaker: Arc<CircuitBreaker>,
+    pub metrics: Arc<Metrics>,
+    /// The `--shared-cache` reading clients reuse within its TTL.
+    pub price_cache: Option<Arc<PriceCache>>,
+    /// Time clients spent waiting on a full aggregator channel.
+    pub backpressure: Arc<Backpressure>,
+}
+
+impl SharedState {
+    pub fn new(config: &ClientConfig) -> Self {
+        SharedState {
+            limiter: config.max_concurrent.map(|permits| Arc::new(Semaphore::new(permits))),
+            rate_limiter: config.rate.map(|rate| Arc::new(RateLimiter::direct(Quota::per_second(rate)))),
+            breaker: Arc::new(CircuitBreaker::new(config.breaker_threshold, config.breaker_cooldown)),
+            metrics: Arc::new(Metrics::new()),
+            price_cache: config.cache_ttl.map(|ttl| Arc::new(PriceCache::new(ttl))),
+            backpressure: Arc::new(Backpressure::default()),
+        }
+    }
+}
+
+/// Builds the HTTP client every simulated client shares, so they reuse one connection pool.
+pub fn build_http_client(config: &ClientConfig) -> Result<reqwest::Client, ClientError> {
+    http_client(config.timeout, config.proxy.as_deref(), &config.headers)
+}
+
+/// The same client for requests made outside a cache run, such as `--mode=list-assets`.
+pub fn http_client(
+    timeout: Duration,
+    proxy: Option<&str>,
+    extra_headers: &[(String, String)],
+) -> Result<reqwest::Client, ClientError> {
+    let mut headers = HeaderMap::new();
+    for (name, value) in extra_headers {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|e| ClientError::Config(format!("invalid header name '{}': {}", name, e)))?;
+        let value = HeaderValue::from_str(value)
+            .map_err(|e| ClientError::Config(format!("invalid value for header '{}': {}", name, e)))?;
+        headers.append(name, value);
+    }
+
+    let mut builder = reqwest::Client::builder()
+        .timeout(timeout)
+        .user_agent(USER_AGENT)
+        .default_headers(headers);
+
+    if let Some(proxy) = proxy {
+        let proxy = reqwest::Proxy::all(proxy)
+            .map_err(|e| ClientError::Config(format!("invalid proxy '{}': {}", redact_proxy(proxy), e)))?;
+        builder = builder.proxy(proxy);
+    }
+
+    Ok(builder.build()?)
+}
+
+/// One request to the primary source before any client starts, so an unreachable API or a
+/// changed response shape fails the run at once instead of after a window of empty ticks.
+pub async fn preflight(config: &ClientConfig, client: &reqwest::Client) -> Result<(), ClientError> {
+    let url = config.spot_url();
+    let sample = CoinbaseSource::new(config)
+        .fetch_price(client)
+        .await
+        .map_err(|source| ClientError::Preflight { url: url.clone(), source })?;
+
+    info!("Preflight: {} answered with {}", url, sample.price);
+    Ok(())
+}
+
+/// The proxy URL with any password removed, for logs and error messages.
+pub fn redact_proxy(proxy: &str) -> String {
+    match reqwest::Url::parse(proxy) {
+        Ok(mut url) if url.password().is_some() => {
+            let _ = url.set_password(Some("***"));
+            url.to_string()
+        }
+        _ => proxy.to_string(),
+    }
+}
+
+/// Polls (or streams) prices until the window closes, then reports to the aggregator.
+///
+/// Every timing decision here goes through `tokio::time` (`Instant`, `sleep_until`,
+/// `timeout_at`) rather than `std::time`, and wall-clock `Utc::now()` is only ever recorded,
+/// never waited on. That keeps the loop drivable by a paused tokio clock: under
+/// `#[tokio::test(start_paused = true)]` (tokio's `test-util` feature) a `--dry-run` client
+/// runs its whole `--times` window, warmup and `--window` included, in milliseconds, since the
+/// runtime jumps the clock forward whenever every task is waiting on a timer. New timing
+/// features should keep to `tokio::time` so this stays true.
+#[tracing::instrument(name = "client", skip_all, fields(client_id = client_id))]
+pub async fn simulate_client(
+    client_id: usize,
+    config: ClientConfig,
+    client: reqwest::Client,
+    start_time: Instant,
+    tx: mpsc::Sender<ClientMessage>,
+    shutdown: CancellationToken,
+    shared: SharedState,
+) -> Result<(), ClientError> {
+    let config = config.for_client(client_id);
+    let sources = build_sources(&config);
+    let mut synthetic = config.synthetic.map(|synthetic| SyntheticPrices::new(synthetic, client_id));
+
+    let mut tally = Tally::new(&config, client_id);
+    let mut spread = config.compare_sources.map(SpreadTally::new);
+
+    if let Some(offset) = config.start_offset(client_id) {
+        debug!("Jitter: starting after {:?}", offset);
+        tokio::select! {
+            _ = tokio::time::sleep(offset) => {}
+            _ = shutdown.cancelled() => {}
+        }
+    }
+
+    let started_at = Utc::now();
+    let loop_start = Instant::now();
+    // `--once` has no window, so its retries run the full schedule
+    let retry_deadline = (!config.once).then(|| config.deadline(start_time));
+
+    if config.transport == Transport::Websocket && synthetic.is_none() {
+        if let Err(e) = stream_prices(&config, start_time, &shutdown, &mut tally, &shared.metrics).await {
+            warn!("WebSocket feed failed ({}), falling back to HTTP polling", e);
+        }
+    }
+
+    // Set when the loop has to stop early; what was collected until then is still reported
+    let mut failure = None;
+
+    while tally.window_open(&config, start_time) && !shutdown.is_cancelled() {
+        let tick_start = Instant::now();
+
+        let in_warmup = start_time.elapsed() < config.warmup;
+        let mut tick_error = None;
+
+        let (outcome, sample) = if let Some(generator) = synthetic.as_mut() {
+            (Outcome::Ok, Some((Sample::price(generator.next_price()), "synthetic")))
+        } else {
+            if let Some(wait) = shared.breaker.wait_time() {
+                debug!("Circuit breaker open, waiting {:?}", wait);
+                // Never past the window, so a long cooldown can't hold the client beyond --times
+                let resume = Instant::now() + wait;
+                let resume = retry_deadline.map_or(resume, |deadline| resume.min(deadline));
+                tokio::select! {
+                    _ = tokio::time::sleep_until(resume) => {}
+                    _ = shutdown.cancelled() => {}
+                }
+                continue;
+            }
+
+            let request = async {
+                if let Some(rate_limiter) = &shared.rate_limiter {
+                    if rate_limiter.check().is_err() {
+                        info!("Throttled by --rate, waiting for a request token");
+                        rate_limiter.until_ready().await;
+                    }
+                }
+                // Held only for the request itself, so waiting clients get in as soon as one finishes
+                let _permit = match &shared.limiter {
+                    Some(limiter) => Some(limiter.acquire().await.expect("request limiter is never closed")),
+                    None => None,
+                };
+                match spread.as_mut() {
+                    // Warmup answers are fetched but, like their prices, not counted
+                    Some(spread) => {
+                        let spread = (!in_warmup).then_some(spread);
+                        fetch_comparing_sources(&sources, &client, retry_deadline, spread).await
+                    }
+                    None => fetch_from_sources(&sources, &client, retry_deadline).await,
+                }
+            };
+            // A cache hit never touches the rate limiter or the concurrency cap
+            let fetch = async {
+                match &shared.price_cache {
+                    Some(cache) => cache.get_or_fetch(request).await,
+                    None => request.await,
+                }
+            };
+            // A request still in flight when the window closes or the run shuts down is abandoned,
+            // not waited for: dropping the future drops reqwest's connection and any retry sleep
+            let outcome = tokio::select! {
+                outcome = fetch => outcome,
+                _ = tokio::time::sleep_until(config.deadline(start_time)), if !config.once => {
+                    debug!("Window ended with a request in flight, abandoning it");
+                    break;
+                }
+                _ = shutdown.cancelled() => {
+                    debug!("Shutting down with a request in flight, abandoning it");
+                    break;
+                }
+            };
+
+            match &outcome {
+                Ok(_) => shared.breaker.record_success(),
+                Err(_) => shared.breaker.record_failure(),
+            }
+            tick_error = outcome.as_ref().err().map(ToString::to_string);
+
+            match outcome {
+                Ok((sample, source)) => (Outcome::Ok, Some((sample, source))),
+                Err(e) if e.is_fatal() => {
+                    error!("Stopping early, {} won't change on a retry", e);
+                    tally.record(Outcome::HttpError, None, in_warmup, &shared.metrics);
+                    if let Some(fail_fast) = &config.fail_fast {
+                        fail_fast.trip(client_id, &e.to_string());
+                    }
+                    failure = Some(ClientError::Unrecoverable(e));
+                    break;
+                }
+                Err(e @ (FetchError::InvalidResponse(_) | FetchError::Decode(_))) => {
+                    warn!("Discarding response: {}", e);
+                    (Outcome::ParseError, None)
+                }
+                Err(e @ (FetchError::RateLimited { .. } | FetchError::Status(_) | FetchError::ContentType(_))) => {
+                    error!("Giving up on this tick after retries: {}", e);
+                    (Outcome::HttpError, None)
+                }
+                Err(FetchError::Request(e)) => {
+                    if e.is_connect() && config.proxy.is_some() {
+                        error!("Could not connect through the proxy, giving up on this tick: {}", e);
+                        (Outcome::ProxyError, None)
+                    } else {
+                        error!("Giving up on this tick after a {}: {}", request_error_kind(&e), e);
+                        let outcome = if e.is_timeout() { Outcome::Timeout } else { Outcome::HttpError };
+                        (outcome, None)
+                    }
+                }
+            }
+        };
+        tally.record(outcome, sample, in_warmup, &shared.metrics);
+
+        if let (Some(fail_fast), Some(e)) = (&config.fail_fast, &tick_error) {
+            failure = Some(ClientError::FailFast(fail_fast.trip(client_id, e)));
+            break;
+        }
+
+        if config.once || tally.reached_max_samples(&config) {
+            break;
+        }
+
+        // Space ticks from their start so slow requests don't stretch the interval
+        let next_tick = (tick_start + config.interval).min(config.deadline(start_time));
+        tokio::select! {
+            _ = tokio::time::sleep_until(next_tick) => {}
+            _ = shutdown.cancelled() => {}
+        }
+    }
+
+    let finished_at = Utc::now();
+    let duration = loop_start.elapsed();
+    let stopped_by = if failure.is_some() {
+        StopReason::Error
+    } else if tally.reached_max_samples(&config) {
+        StopReason::MaxSamples
+    } else if config.once {
+        StopReason::Once
+    } else if shutdown.is_cancelled() {
+        StopReason::Shutdown
+    } else {
+        StopReason::Window
+    };
+
+    let Tally {
+        mut running,
+        requests,
+        samples_by_source,
+        warmup_discarded,
+        ..
+    } = tally;
+    if warmup_discarded > 0 {
+        debug!("Discarded {} warmup sample(s)", warmup_discarded);
+    }
+
+    if requests.failures() > 0 {
+        warn!("Client {}: {} in {:.2}s", client_id, requests, duration.as_secs_f64());
+    } else {
+        info!("Client {}: {} in {:.2}s", client_id, requests, duration.as_secs_f64());
+    }
+
+    // A client without samples has no average; reporting one would turn the aggregate into NaN
+    let Some(average) = running.value() else {
+        warn!("No successful samples, not reporting an average");
+        send_to_aggregator(&tx, ClientMessage::NoSamples { client_id, requests }, client_id, &shared).await;
+        return failure.map_or(Ok(()), Err);
+    };
+    info!(
+        "Average {} price of {} is: {:.*} (ran {} to {})",
+        config.currency,
+        config.asset,
+        config.precision,
+        average,
+        started_at.to_rfc3339(),
+        finished_at.to_rfc3339()
+    );
+
+    // Send the average to the aggregator
+    let report = ClientReport {
+        client_id,
+        average,
+        samples: running.count,
+        prices: running.prices,
+        samples_by_source,
+        requests,
+        started_at: Some(started_at),
+        finished_at: Some(finished_at),
+        duration_secs: duration.as_secs_f64(),
+        interval_ms: config.interval.as_millis() as u64,
+        timeout_ms: config.timeout.as_millis() as u64,
+        stopped_by,
+        error: failure.as_ref().map(|e| e.to_string()),
+        source_spread: spread.map(|spread| Box::new(spread.into_spread())),
+    };
+    send_to_aggregator(&tx, ClientMessage::Report(report), client_id, &shared).await;
+
+    failure.map_or(Ok(()), Err)
+}
+
+/// Hands the client's result to the aggregator. When `--channel-capacity` is used up, the
+/// wait is logged and recorded against the client, since it means the aggregator is slow.
+async fn send_to_aggregator(
+    tx: &mpsc::Sender<ClientMessage>,
+    message: ClientMessage,
+    client_id: usize,
+    shared: &SharedState,
+) {
+    let sent = match tx.try_send(message) {
+        Ok(()) => true,
+        Err(TrySendError::Closed(_)) => false,
+        Err(TrySendError::Full(message)) => {
+            warn!("Aggregator channel is full, waiting to report");
+            let waiting_since = Instant::now();
+            let sent = tx.send(message).await.is_ok();
+            let blocked = waiting_since.elapsed();
+            debug!("Blocked on the aggregator channel for {:?}", blocked);
+            shared.backpressure.record(client_id, blocked);
+            sent
+        }
+    };
+    if !sent {
+        error!("Aggregator is no longer accepting results");
+    }
+}
+
+/// Asks each source in turn, moving on only after the previous one exhausted its retries.
+async fn fetch_from_sources<'a>(
+    sources: &'a [Box<dyn PriceSource>],
+    client: &reqwest::Client,
+    deadline: Option<Instant>,
+) -> Result<(Sample, &'a str), FetchError> {
+    let mut last_error = None;
+
+    for source in sources {
+        match fetch_with_retry(source.as_ref(), client, deadline).await {
+            Ok(sample) => return Ok((sample, source.name())),
+            Err(e) => {
+                if sources.len() > 1 {
+                    warn!("Source '{}' failed: {}", source.name(), e);
+                }
+                last_error = Some(e);
+            }
+        }
+    }
+
+    Err(last_error.expect("at least one price source is configured"))
+}
+
+/// Asks every source at once, each with its own retries, and records how far apart their
+/// answers are. The price kept is the first answering source's, as `fetch_from_sources` would
+/// pick it.
+async fn fetch_comparing_sources<'a>(
+    sources: &'a [Box<dyn PriceSource>],
+    client: &reqwest::Client,
+    deadline: Option<Instant>,
+    spread: Option<&mut SpreadTally>,
+) -> Result<(Sample, &'a str), FetchError> {
+    let results = join_all(sources.iter().map(|source| async move {
+        (source.name(), fetch_with_retry(source.as_ref(), client, deadline).await)
+    }))
+    .await;
+
+    let mut readings = Vec::new();
+    let mut last_error = None;
+    for (name, result) in results {
+        match result {
+            Ok(sample) => readings.push((name, sample)),
+            Err(e) => {
+                warn!("Source '{}' failed: {}", name, e);
+                last_error = Some(e);
+            }
+        }
+    }
+    if let Some(spread) = spread {
+        let prices: Vec<(&str, f64)> = readings.iter().map(|&(name, sample)| (name, sample.price)).collect();
+        spread.record(&prices);
+    }
+
+    match readings.first() {
+        Some(&(name, sample)) => Ok((sample, name)),
+        None => Err(last_error.expect("at least one price source is configured")),
+    }
+}
+
+/// Fetches one spot price, retrying with exponential backoff on request or decode failures, but
+/// never past `deadline`: once the next wait would end after the window closes, the last error
+/// is returned so the client can wrap up instead.
+async fn fetch_with_retry(
+    source: &dyn PriceSource,
+    client: &reqwest::Client,
+    deadline: Option<Instant>,
+) -> Result<Sample, FetchError> {
+    let mut attempt = 0;
+
+    loop {
+        match source.fetch_price(client).await {
+            Ok(sample) => return Ok(sample),
+            Err(e) if !e.is_retryable() => {
+                debug!("Not retrying {} ({}), it would fail the same way", source.name(), e.kind());
+                return Err(e);
+            }
+            Err(e) if attempt >= RETRY_BACKOFF_MS.len() => return Err(e),
+            Err(e) => {
+                let delay = match e.retry_after() {
+                    Some(retry_after) => retry_after.min(MAX_RETRY_AFTER),
+                    None => Duration::from_millis(RETRY_BACKOFF_MS[attempt]),
+                };
+                if deadline.is_some_and(|deadline| Instant::now() + delay >= deadline) {
+                    debug!("Not retrying, the window closes before the next attempt");
+                    return Err(e);
+                }
+                if e.retry_after().is_some() {
+                    info!("Rate limited, waiting {:.1}s as Retry-After asks", delay.as_secs_f64());
+                } else {
+                    debug!("Retrying {} in {:?} after a {}: {}", source.name(), delay, e.kind(), e);
+                }
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::CoinbaseSource;
+    use crate::testing::{
+        dead_url, dry_run_config, run_client, run_client_until, run_clients, test_config, MockResponse, MockServer,
+    };
+
+    fn expect_report(message: ClientMessage) -> ClientReport {
+        match message {
+            ClientMessage::Report(report) => report,
+            ClientMessage::NoSamples { requests, .. } => panic!("expected a report, got no samples ({})", requests),
+        }
+    }
+
+    fn expect_no_samples(message: ClientMessage) -> RequestStats {
+        match message {
+            ClientMessage::NoSamples { requests, .. } => requests,
+            ClientMessage::Report(report) => panic!("expected no samples, got an average of {}", report.average),
+        }
+    }
+
+    #[tokio::test]
+    async fn a_fetch_that_fails_twice_succeeds_on_the_third_attempt() {
+        let server = MockServer::start(vec![
+            MockResponse::status(500),
+            MockResponse::status(503),
+            MockResponse::price("100.00"),
+        ])
+        .await;
+        let config = test_config(&server.url());
+        let http_client = build_http_client(&config).expect("test HTTP client builds");
+
+        let price = fetch_with_retry(&CoinbaseSource::new(&config), &http_client, None)
+            .await
+            .expect("third attempt succeeds");
+
+        assert_eq!(price.price, 100.0);
+        assert_eq!(server.requests(), 3);
+    }
+
+    #[tokio::test]
+    async fn a_fetch_gives_up_once_every_retry_has_failed() {
+        let server = MockServer::start(vec![MockResponse::status(500)]).await;
+        let config = test_config(&server.url());
+        let http_client = build_http_client(&config).expect("test HTTP client builds");
+
+        let result = fetch_with_retry(&CoinbaseSource::new(&config), &http_client, None).await;
+
+        assert!(result.is_err());
+        assert_eq!(server.requests(), RETRY_BACKOFF_MS.len() + 1);
+    }
+
+    #[tokio::test]
+    async fn averages_the_prices_the_server_returns() {
+        let server = MockServer::start(vec![MockResponse::price("100.00"), MockResponse::price("200.00")]).await;
+        let config = ClientConfig {
+            max_samples: Some(2),
+            ..test_config(&server.url())
+        };
+
+        let (result, message) = run_client(config).await;
+
+        result.expect("client succeeds");
+        let report = expect_report(message);
+        assert_eq!(report.samples, 2);
+        assert_eq!(report.average, 150.0);
+        assert_eq!(report.requests.ok, 2);
+        assert_eq!(report.stopped_by, StopReason::MaxSamples);
+    }
+
+    #[tokio::test]
+    async fn a_tick_that_succeeds_on_its_third_attempt_counts_once_as_ok() {
+        let server = MockServer::start(vec![
+            MockResponse::status(500),
+            MockResponse::status(500),
+            MockResponse::price("100.00"),
+        ])
+        .await;
+        let config = ClientConfig {
+            once: true,
+            ..test_config(&server.url())
+        };
+
+        let (result, message) = run_client(config).await;
+
+        result.expect("client succeeds");
+        let report = expect_report(message);
+        assert_eq!(report.requests.ok, 1);
+        assert_eq!(report.requests.http_errors, 0);
+        assert_eq!(report.average, 100.0);
+        assert_eq!(server.requests(), 3);
+    }
+
+    #[tokio::test]
+    async fn an_unparseable_amount_leaves_the_average_alone() {
+        let server = MockServer::start(vec![
+            MockResponse::price("100.00"),
+            MockResponse::price("not_a_number"),
+            MockResponse::price("200.00"),
+        ])
+        .await;
+        let config = ClientConfig {
+            max_samples: Some(2),
+            ..test_config(&server.url())
+        };
+
+        let (result, message) = run_client(config).await;
+
+        result.expect("a bad amount is not fatal");
+        let report = expect_report(message);
+        assert_eq!(report.average, 150.0);
+        assert_eq!(report.requests.ok, 2);
+        assert_eq!(report.requests.parse_errors, 1);
+        assert_eq!(server.requests(), 3, "a bad amount is not retried");
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_retried_and_counted_as_http_errors() {
+        let server = MockServer::start(vec![MockResponse::status(503)]).await;
+
+        let (result, message) = run_client(test_config(&server.url())).await;
+
+        result.expect("a 503 is not fatal");
+        let requests = expect_no_samples(message);
+        assert_eq!(requests.ok, 0);
+        assert!(requests.http_errors >= 1);
+        assert_eq!(requests.parse_errors, 0);
+        assert!(server.requests() > requests.http_errors, "each failed tick retries first");
+    }
+
+    #[tokio::test]
+    async fn malformed_json_counts_as_a_parse_error() {
+        let server = MockServer::start(vec![MockResponse::json(r#"{"data": {"amount""#)]).await;
+
+        let (result, message) = run_client(test_config(&server.url())).await;
+
+        result.expect("a malformed body is not fatal");
+        let requests = expect_no_samples(message);
+        assert_eq!(requests.ok, 0);
+        assert!(requests.parse_errors >= 1);
+        assert_eq!(requests.http_errors, 0);
+    }
+
+    /// The pattern `simulate_client` documents: a paused clock jumps straight to each timer, so
+    /// a minute-long window with warmup and `--window` runs in milliseconds of real time.
+    #[tokio::test(start_paused = true)]
+    async fn a_paused_clock_runs_a_whole_window_instantly() {
+        let config = ClientConfig {
+            times: 60,
+            interval: Duration::from_secs(1),
+            warmup: Duration::from_secs(5),
+            window: Some(Duration::from_secs(10)),
+            ..dry_run_config(42)
+        };
+        let real_start = std::time::Instant::now();
+
+        let (result, message) = run_client(config).await;
+
+        result.expect("dry runs succeed");
+        let report = expect_report(message);
+        // A tick at each of seconds 0-59, less the five inside the warmup
+        assert_eq!(report.samples, 55);
+        assert_eq!(report.stopped_by, StopReason::Window);
+        assert!(report.duration_secs >= 59.0);
+        assert!(real_start.elapsed() < std::time::Duration::from_secs(5));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_half_second_interval_samples_twice_a_second() {
+        let config = ClientConfig {
+            times: 2,
+            interval: Duration::from_millis(500),
+            ..dry_run_config(7)
+        };
+
+        let (result, message) = run_client(config).await;
+
+        result.expect("dry runs succeed");
+        assert_eq!(expect_report(message).samples, 4);
+    }
+
+    /// One request in flight at a time, so a shared pool always has an idle connection to hand
+    /// the next client, where per-client pools would each open their own.
+    #[tokio::test]
+    async fn clients_share_one_connection_pool() {
+        let server = MockServer::start(vec![MockResponse::price("100.00")]).await;
+        let config = ClientConfig {
+            max_samples: Some(2),
+            max_concurrent: Some(1),
+            ..test_config(&server.url())
+        };
+
+        let messages = run_clients(config, 4).await;
+
+        assert_eq!(messages.len(), 4);
+        assert_eq!(server.requests(), 8);
+        assert!(server.connections() < 4, "{} connections for 4 clients", server.connections());
+    }
+
+    #[tokio::test]
+    async fn max_concurrent_caps_requests_in_flight_across_clients() {
+        let server = MockServer::start(vec![MockResponse::price("100.00").delayed(Duration::from_millis(50))]).await;
+        let config = ClientConfig {
+            max_samples: Some(2),
+            max_concurrent: Some(2),
+            ..test_config(&server.url())
+        };
+
+        run_clients(config, 6).await;
+
+        assert_eq!(server.requests(), 12);
+        assert_eq!(server.max_in_flight(), 2);
+    }
+
+    #[tokio::test]
+    async fn an_open_breaker_does_not_outlast_the_window() {
+        let config = ClientConfig {
+            times: 2,
+            breaker_threshold: 1,
+            breaker_cooldown: Duration::from_secs(30),
+            ..test_config(&dead_url().await)
+        };
+        let start = Instant::now();
+
+        let (result, message) = run_client(config).await;
+
+        result.expect("an unreachable API is not fatal");
+        assert!(expect_no_samples(message).http_errors >= 1);
+        assert!(start.elapsed() < Duration::from_secs(4), "took {:?}", start.elapsed());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_client_discards_the_samples_inside_its_warmup() {
+        let config = ClientConfig {
+            times: 4,
+            interval: Duration::from_secs(1),
+            warmup: Duration::from_secs(2),
+            ..dry_run_config(3)
+        };
+
+        let (result, message) = run_client(config).await;
+
+        result.expect("dry runs succeed");
+        let report = expect_report(message);
+        assert_eq!(report.samples, 2);
+        assert_eq!(report.requests.ok, 4);
+    }
+
+    /// The API host doesn't resolve, so a price can only arrive through the proxy.
+    #[tokio::test]
+    async fn requests_go_through_the_configured_proxy() {
+        let proxy = MockServer::start(vec![MockResponse::price("100.00")]).await;
+        let config = ClientConfig {
+            once: true,
+            proxy: Some(proxy.url()),
+            ..test_config("http://price-api.invalid")
+        };
+
+        let (result, message) = run_client(config).await;
+
+        result.expect("client succeeds through the proxy");
+        assert_eq!(expect_report(message).average, 100.0);
+        assert_eq!(proxy.requests(), 1);
+    }
+
+    #[tokio::test]
+    async fn a_request_in_flight_when_the_window_closes_is_abandoned() {
+        let server = MockServer::start(vec![
+            MockResponse::price("100.00"),
+            MockResponse::price("200.00").delayed(Duration::from_secs(10)),
+        ])
+        .await;
+        let start = Instant::now();
+
+        let (result, message) = run_client(test_config(&server.url())).await;
+
+        result.expect("client succeeds");
+        let report = expect_report(message);
+        assert_eq!(report.samples, 1);
+        assert_eq!(report.average, 100.0);
+        assert!(start.elapsed() < Duration::from_secs(2), "took {:?}", start.elapsed());
+    }
+
+    #[tokio::test]
+    async fn a_rate_limited_request_waits_out_retry_after() {
+        let server = MockServer::start(vec![
+            MockResponse::status(429).header("Retry-After", "1"),
+            MockResponse::price("100.00"),
+        ])
+        .await;
+        let config = ClientConfig {
+            once: true,
+            ..test_config(&server.url())
+        };
+        let start = Instant::now();
+
+        let (result, message) = run_client(config).await;
+
+        result.expect("client succeeds");
+        assert_eq!(expect_report(message).requests.ok, 1);
+        assert!(start.elapsed() >= Duration::from_secs(1), "retried after {:?}", start.elapsed());
+        assert_eq!(server.requests(), 2);
+    }
+
+    #[tokio::test]
+    async fn retries_stop_once_the_next_wait_would_pass_the_deadline() {
+        let server = MockServer::start(vec![MockResponse::status(503)]).await;
+        let config = test_config(&server.url());
+        let source = CoinbaseSource::new(&config);
+        let http_client = build_http_client(&config).expect("test HTTP client builds");
+        let start = Instant::now();
+
+        let result = fetch_with_retry(&source, &http_client, Some(start + Duration::from_millis(600))).await;
+
+        assert!(matches!(result, Err(FetchError::Status(_))), "got {:?}", result);
+        // The 250ms wait fits before the deadline, the 500ms after it doesn't
+        assert_eq!(server.requests(), 2);
+        assert!(start.elapsed() < Duration::from_millis(600), "took {:?}", start.elapsed());
+    }
+
+    #[tokio::test]
+    async fn without_a_deadline_every_retry_is_tried() {
+        let server = MockServer::start(vec![MockResponse::status(503)]).await;
+        let config = test_config(&server.url());
+        let http_client = build_http_client(&config).expect("test HTTP client builds");
+
+        let result = fetch_with_retry(&CoinbaseSource::new(&config), &http_client, None).await;
+
+        assert!(result.is_err());
+        assert_eq!(server.requests(), RETRY_BACKOFF_MS.len() + 1);
+    }
+
+    #[tokio::test]
+    async fn a_client_that_fails_for_good_still_reports_what_it_had() {
+        let server = MockServer::start(vec![MockResponse::price("100.00"), MockResponse::status(401)]).await;
+
+        let (result, message) = run_client(test_config(&server.url())).await;
+
+        assert!(matches!(result, Err(ClientError::Unrecoverable(_))), "got {:?}", result);
+        let report = expect_report(message);
+        assert_eq!(report.samples, 1);
+        assert_eq!(report.average, 100.0);
+        assert!(report.error.is_some_and(|error| error.contains("401")));
+        assert_eq!(server.requests(), 2, "a 401 is neither retried nor polled again");
+    }
+
+    #[tokio::test]
+    async fn an_unbuildable_request_stops_the_client_at_once() {
+        let start = Instant::now();
+
+        let (result, message) = run_client(test_config("http://exa mple.com")).await;
+
+        assert!(matches!(result, Err(ClientError::Unrecoverable(_))), "got {:?}", result);
+        assert_eq!(expect_no_samples(message).http_errors, 1);
+        assert!(start.elapsed() < Duration::from_millis(250), "retried for {:?}", start.elapsed());
+    }
+
+    #[tokio::test]
+    async fn a_shutdown_abandons_the_request_in_flight() {
+        let server = MockServer::start(vec![
+            MockResponse::price("100.00"),
+            MockResponse::price("200.00").delayed(Duration::from_secs(10)),
+        ])
+        .await;
+        let shutdown = CancellationToken::new();
+        let trigger = shutdown.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            trigger.cancel();
+        });
+        let start = Instant::now();
+
+        let (result, message) = run_client_until(ClientConfig { times: 30, ..test_config(&server.url()) }, shutdown).await;
+
+        result.expect("client succeeds");
+        let report = expect_report(message);
+        assert_eq!(report.samples, 1);
+        assert_eq!(report.average, 100.0);
+        assert!(start.elapsed() < Duration::from_secs(1), "took {:?}", start.elapsed());
+    }
+}
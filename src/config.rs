@@ -0,0 +1,429 @@
+use crate::cli::{parse_duration_secs, Cli, MAX_CLIENTS, MAX_TIMES_SECS, MIN_INTERVAL_MS};
+use crate::client::redact_proxy;
+use crate::error::ClientError;
+use clap::{Arg, Command, CommandFactory};
+use serde::Deserialize;
+use std::ffi::OsString;
+use std::fmt;
+use std::path::Path;
+
+/// Flag values loaded from a `--config` TOML file. Keys are the long flag names with
+/// underscores, e.g. `log_level = "debug"`; anything omitted keeps its usual default.
+/// Unknown keys are rejected, so a typo can't silently leave a setting at its default.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileConfig {
+    pub mode: Option<String>,
+    pub times: Option<Times>,
+    pub once: Option<bool>,
+    pub max_samples: Option<usize>,
+    pub warmup: Option<u64>,
+    /// Two assets, e.g. `["BTC", "ETH"]`.
+    pub compare: Option<Vec<String>>,
+    pub asset: Option<String>,
+    pub allowed_assets: Option<Vec<String>>,
+    pub currency: Option<String>,
+    pub clients: Option<u64>,
+    pub channel_capacity: Option<u64>,
+    pub fail_fast: Option<bool>,
+    pub max_concurrent: Option<u64>,
+    pub rate: Option<u32>,
+    pub shared_cache: Option<bool>,
+    pub cache_ttl: Option<u64>,
+    pub breaker_threshold: Option<u64>,
+    pub breaker_cooldown: Option<u64>,
+    pub timeout: Option<u64>,
+    pub timeout_total: Option<u64>,
+    pub proxy: Option<String>,
+    /// Each entry is one `--header`, e.g. `"X-Team: pricing"`.
+    pub headers: Option<Vec<String>>,
+    pub interval: Option<u64>,
+    pub window: Option<u64>,
+    pub jitter: Option<bool>,
+    pub clients_vary: Option<bool>,
+    pub no_file: Option<bool>,
+    pub output_precision: Option<usize>,
+    pub preflight: Option<bool>,
+    pub append: Option<bool>,
+    pub avg: Option<String>,
+    pub aggregate: Option<String>,
+    pub trim: Option<f64>,
+    pub alpha: Option<f64>,
+    pub reject_outliers: Option<f64>,
+    pub min_price: Option<f64>,
+    pub max_price: Option<f64>,
+    pub alert_change: Option<f64>,
+    pub endpoint: Option<String>,
+    pub transport: Option<String>,
+    pub parser: Option<String>,
+    pub fallback_url: Option<String>,
+    pub fallback_parser: Option<String>,
+    pub compare_sources: Option<bool>,
+    pub spread_threshold: Option<f64>,
+    pub dry_run: Option<bool>,
+    pub base_price: Option<f64>,
+    pub seed: Option<u64>,
+    pub format: Option<String>,
+    pub output: Option<String>,
+    pub input: Option<String>,
+    pub addr: Option<String>,
+    pub metrics_addr: Option<String>,
+    pub store: Option<String>,
+    pub db: Option<String>,
+    pub trend: Option<bool>,
+    pub follow: Option<bool>,
+    pub log_level: Option<String>,
+    pub quiet: Option<bool>,
+    pub json: Option<bool>,
+    pub verbose: Option<bool>,
+}
+
+impl FileConfig {
+    pub fn load(path: &Path) -> Result<Self, ClientError> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| ClientError::Config(format!("could not read config {}: {}", path.display(), e)))?;
+
+        let config: FileConfig = toml::from_str(&contents)
+            .map_err(|e| ClientError::Config(format!("invalid config {}: {}", path.display(), e)))?;
+        config
+            .validate()
+            .map_err(|reason| ClientError::Config(format!("invalid config {}: {}", path.display(), reason)))?;
+
+        Ok(config)
+    }
+
+    /// Range checks for the settings most often mistyped, so the error names the file and key
+    /// rather than a flag the user never typed.
+    fn validate(&self) -> Result<(), String> {
+        let times = self
+            .times
+            .as_ref()
+            .map(|times| parse_duration_secs(&times.to_string()).map_err(|e| format!("`times`: {}", e)))
+            .transpose()?;
+        check_range("times", times, 1, MAX_TIMES_SECS)?;
+        check_range("clients", self.clients, 1, MAX_CLIENTS)?;
+        check_range("interval", self.interval, MIN_INTERVAL_MS, u64::MAX)?;
+        Ok(())
+    }
+
+    /// The file's values as `--flag=value` arguments, so clap validates them like typed flags.
+    /// Boolean flags set to `false` are left out, as there is no flag to spell them.
+    pub fn to_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+
+        push_value(&mut args, "mode", &self.mode);
+        push_value(&mut args, "times", &self.times);
+        push_flag(&mut args, "once", self.once);
+        push_value(&mut args, "max-samples", &self.max_samples);
+        push_value(&mut args, "warmup", &self.warmup);
+        if let Some(compare) = &self.compare {
+            args.push(format!("--compare={}", compare.join(",")));
+        }
+        push_value(&mut args, "asset", &self.asset);
+        push_value(&mut args, "allowed-assets", &self.allowed_assets.as_ref().map(|assets| assets.join(",")));
+        push_value(&mut args, "currency", &self.currency);
+        push_value(&mut args, "clients", &self.clients);
+        push_value(&mut args, "channel-capacity", &self.channel_capacity);
+        push_flag(&mut args, "fail-fast", self.fail_fast);
+        push_value(&mut args, "max-concurrent", &self.max_concurrent);
+        push_value(&mut args, "rate", &self.rate);
+        push_flag(&mut args, "shared-cache", self.shared_cache);
+        push_value(&mut args, "cache-ttl", &self.cache_ttl);
+        push_value(&mut args, "breaker-threshold", &self.breaker_threshold);
+        push_value(&mut args, "breaker-cooldown", &self.breaker_cooldown);
+        push_value(&mut args, "timeout", &self.timeout);
+        push_value(&mut args, "timeout-total", &self.timeout_total);
+        push_value(&mut args, "proxy", &self.proxy);
+        for header in self.headers.iter().flatten() {
+            push_value(&mut args, "header", &Some(header));
+        }
+        push_value(&mut args, "interval", &self.interval);
+        push_value(&mut args, "window", &self.window);
+        push_flag(&mut args, "jitter", self.jitter);
+        push_flag(&mut args, "clients-vary", self.clients_vary);
+        push_flag(&mut args, "no-file", self.no_file);
+        push_value(&mut args, "output-precision", &self.output_precision);
+        push_flag(&mut args, "no-preflight", self.preflight.map(|preflight| !preflight));
+        push_flag(&mut args, "append", self.append);
+        push_value(&mut args, "avg", &self.avg);
+        push_value(&mut args, "aggregate", &self.aggregate);
+        push_value(&mut args, "trim", &self.trim);
+        push_value(&mut args, "alpha", &self.alpha);
+        push_value(&mut args, "reject-outliers", &self.reject_outliers);
+        push_value(&mut args, "min-price", &self.min_price);
+        push_value(&mut args, "max-price", &self.max_price);
+        push_value(&mut args, "alert-change", &self.alert_change);
+        push_value(&mut args, "endpoint", &self.endpoint);
+        push_value(&mut args, "transport", &self.transport);
+        push_value(&mut args, "parser", &self.parser);
+        push_value(&mut args, "fallback-url", &self.fallback_url);
+        push_value(&mut args, "fallback-parser", &self.fallback_parser);
+        push_flag(&mut args, "compare-sources", self.compare_sources);
+        push_value(&mut args, "spread-threshold", &self.spread_threshold);
+        push_flag(&mut args, "dry-run", self.dry_run);
+        push_value(&mut args, "base-price", &self.base_price);
+        push_value(&mut args, "seed", &self.seed);
+        push_value(&mut args, "format", &self.format);
+        push_value(&mut args, "output", &self.output);
+        push_value(&mut args, "input", &self.input);
+        push_value(&mut args, "addr", &self.addr);
+        push_value(&mut args, "metrics-addr", &self.metrics_addr);
+        push_value(&mut args, "store", &self.store);
+        push_value(&mut args, "db", &self.db);
+        push_flag(&mut args, "trend", self.trend);
+        push_flag(&mut args, "follow", self.follow);
+        push_value(&mut args, "log-level", &self.log_level);
+        push_flag(&mut args, "quiet", self.quiet);
+        push_flag(&mut args, "json", self.json);
+        push_flag(&mut args, "verbose", self.verbose);
+
+        args
+    }
+}
+
+fn push_value<T: ToString>(args: &mut Vec<String>, flag: &str, value: &Option<T>) {
+    if let Some(value) = value {
+        args.push(format!("--{}={}", flag, value.to_string()));
+    }
+}
+
+/// `times = 90` or `times = "2m"`, like the flag.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Times {
+    Secs(u64),
+    Duration(String),
+}
+
+impl fmt::Display for Times {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Times::Secs(secs) => write!(f, "{}", secs),
+            Times::Duration(duration) => f.write_str(duration),
+        }
+    }
+}
+
+fn check_range(key: &str, value: Option<u64>, min: u64, max: u64) -> Result<(), String> {
+    match value {
+        Some(value) if value < min || value > max => Err(if max == u64::MAX {
+            format!("`{}` = {} must be at least {}", key, value, min)
+        } else {
+            format!("`{}` = {} must be between {} and {}", key, value, min, max)
+        }),
+        _ => Ok(()),
+    }
+}
+
+fn push_flag(args: &mut Vec<String>, flag: &str, value: Option<bool>) {
+    if value == Some(true) {
+        args.push(format!("--{}", flag));
+    }
+}
+
+/// Finds `--config=<path>` or `--config <path>` before clap runs, since the file has to be
+/// spliced into the arguments clap parses.
+fn config_path(args: &[OsString]) -> Option<OsString> {
+    let mut iter = args.iter().skip(1);
+
+    while let Some(arg) = iter.next() {
+        let arg_str = arg.to_string_lossy();
+        if let Some(path) = arg_str.strip_prefix("--config=") {
+            return Some(OsString::from(path));
+        }
+        if arg_str == "--config" {
+            return iter.next().cloned();
+        }
+    }
+
+    None
+}
+
+/// The `--name` part of a long flag, with any `=value` removed.
+fn flag_name(arg: &str) -> Option<&str> {
+    arg.starts_with("--").then(|| arg.split('=').next().unwrap_or(arg))
+}
+
+/// The arguments of `command` that `arg` spells: one for `--name` or `--name=value`, one per
+/// letter for `-q` or `-qv`, none for a value.
+fn given_args<'a>(command: &'a Command, arg: &str) -> Vec<&'a Arg> {
+    if let Some(name) = flag_name(arg) {
+        let long = &name[2..];
+        return command.get_arguments().filter(|a| a.get_long() == Some(long)).collect();
+    }
+    match arg.strip_prefix('-') {
+        Some(shorts) if !shorts.is_empty() => shorts
+            .chars()
+            .filter_map(|short| command.get_arguments().find(|a| a.get_short() == Some(short)))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// `--name` of every flag the command line sets, and of every flag clap would reject
+/// alongside one of those, e.g. `--times` and `--warmup` when `--once` is typed.
+fn overridden_flags(args: &[OsString]) -> Vec<String> {
+    let command = Cli::command();
+    let given: Vec<&Arg> = args
+        .iter()
+        .skip(1)
+        .flat_map(|arg| given_args(&command, &arg.to_string_lossy()))
+        .collect();
+    let conflict = |a: &Arg, b: &Arg| command.get_arg_conflicts_with(a).iter().any(|c| c.get_id() == b.get_id());
+
+    command
+        .get_arguments()
+        .filter(|arg| {
+            given
+                .iter()
+                .any(|g| g.get_id() == arg.get_id() || conflict(g, arg) || conflict(arg, g))
+        })
+        .filter_map(|arg| arg.get_long().map(|long| format!("--{}", long)))
+        .collect()
+}
+
+/// Builds the argument list clap sees: program name, then file values for every flag the
+/// command line doesn't set, then the real command line. So the command line wins over the
+/// file, which wins over the built-in defaults. File values for flags that conflict with one
+/// on the command line are dropped too, as the typed flag is meant to replace them.
+pub fn layered_args(args: Vec<OsString>) -> Result<Vec<OsString>, ClientError> {
+    let Some(path) = config_path(&args) else {
+        return Ok(args);
+    };
+
+    let file = FileConfig::load(Path::new(&path))?;
+    let overridden = overridden_flags(&args);
+
+    let mut iter = args.into_iter();
+    let mut layered: Vec<OsString> = iter.next().into_iter().collect();
+    layered.extend(
+        file.to_args()
+            .into_iter()
+            .filter(|arg| flag_name(arg).is_some_and(|name| !overridden.iter().any(|o| o == name)))
+            .map(OsString::from),
+    );
+    layered.extend(iter);
+
+    Ok(layered)
+}
+
+/// The effective arguments as recorded with a run: without the program name, and with
+/// `--header` values and proxy passwords masked since result files get shared.
+pub fn recorded_args(args: &[OsString]) -> Vec<String> {
+    let mut recorded = Vec::new();
+    let mut iter = args.iter().skip(1).map(|arg| arg.to_string_lossy().into_owned());
+
+    while let Some(arg) = iter.next() {
+        match flag_name(&arg) {
+            Some("--header") | Some("--proxy") if !arg.contains('=') => {
+                recorded.push(arg.clone());
+                if let Some(value) = iter.next() {
+                    recorded.push(redact_value(&arg, &value));
+                }
+            }
+            Some(flag @ ("--header" | "--proxy")) => {
+                let value = &arg[flag.len() + 1..];
+                recorded.push(format!("{}={}", flag, redact_value(flag, value)));
+            }
+            _ => recorded.push(arg),
+        }
+    }
+
+    recorded
+}
+
+fn redact_value(flag: &str, value: &str) -> String {
+    match (flag, value.split_once(':')) {
+        ("--header", Some((name, _))) => format!("{}: ***", name),
+        ("--header", None) => "***".to_string(),
+        _ => redact_proxy(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use clap::Parser;
+
+    fn load(toml: &str) -> Result<FileConfig, String> {
+        let dir = TempDir::new();
+        FileConfig::load(&dir.file("config.toml", toml)).map_err(|e| e.to_string())
+    }
+
+    /// Parses `args` after a `--config` file holding `toml`, the way `main` does.
+    fn parse_with_file(toml: &str, args: &[&str]) -> Result<Cli, String> {
+        let dir = TempDir::new();
+        let path = dir.file("config.toml", toml);
+        let mut command_line = vec![
+            OsString::from("simulated_distributed_client"),
+            OsString::from("--mode=cache"),
+            OsString::from(format!("--config={}", path.display())),
+        ];
+        command_line.extend(args.iter().map(OsString::from));
+
+        let layered = layered_args(command_line).map_err(|e| e.to_string())?;
+        Cli::try_parse_from(layered).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn file_values_fill_in_and_the_command_line_wins() {
+        let cli = parse_with_file("clients = 10\ntimeout = 3", &["--clients=2"]).expect("valid layering");
+
+        assert_eq!(cli.clients, 2);
+        assert_eq!(cli.timeout, 3);
+    }
+
+    #[test]
+    fn a_typed_flag_drops_the_file_values_it_conflicts_with() {
+        let cli = parse_with_file("times = 60\nwarmup = 5\nquiet = true\nclients = 3", &["--once", "--verbose"])
+            .expect("the typed flags replace the file's");
+
+        assert!(cli.once);
+        assert_eq!(cli.times, None);
+        assert_eq!(cli.warmup, 0);
+        assert!(cli.verbose);
+        assert!(!cli.quiet);
+        assert_eq!(cli.clients, 3);
+    }
+
+    #[test]
+    fn short_flags_drop_conflicting_file_values_too() {
+        let cli = parse_with_file("verbose = true", &["-q"]).expect("-q replaces the file's verbose");
+
+        assert!(cli.quiet);
+        assert!(!cli.verbose);
+    }
+
+    #[test]
+    fn conflicts_within_the_command_line_are_still_rejected() {
+        let err = parse_with_file("clients = 3", &["--once", "--times=5"]).unwrap_err();
+
+        assert!(err.contains("cannot be used with"), "{}", err);
+    }
+
+    #[test]
+    fn an_unknown_key_is_rejected_by_name() {
+        let err = load("clients = 3\nclinets = 4").unwrap_err();
+
+        assert!(err.contains("unknown field `clinets`"), "{}", err);
+    }
+
+    #[test]
+    fn an_out_of_range_value_is_rejected_with_its_key() {
+        let err = load("clients = 0").unwrap_err();
+        assert!(err.contains("`clients` = 0 must be between 1 and 100"), "{}", err);
+
+        let err = load("interval = 50").unwrap_err();
+        assert!(err.contains("`interval` = 50 must be at least 100"), "{}", err);
+
+        let err = load(r#"times = "2h""#).unwrap_err();
+        assert!(err.contains("`times` = 7200 must be between 1 and 3600"), "{}", err);
+    }
+
+    #[test]
+    fn times_takes_seconds_or_a_duration() {
+        assert_eq!(load("times = 90").unwrap().to_args(), ["--times=90"]);
+        assert_eq!(load(r#"times = "2m""#).unwrap().to_args(), ["--times=2m"]);
+    }
+}
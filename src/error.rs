@@ -0,0 +1,143 @@
+use thiserror::Error;
+
+/// Process exit statuses, also listed in `--help`.
+pub const EXIT_FAILURE: u8 = 1;
+/// Bad flags, config file or environment; clap uses the same code for its own usage errors.
+pub const EXIT_USAGE: u8 = 2;
+/// The run collected no prices, or read/stats mode found no recorded runs.
+pub const EXIT_NO_DATA: u8 = 3;
+/// The price API could not be reached or answered unusably.
+pub const EXIT_NETWORK: u8 = 4;
+/// `--alert-change` fired.
+pub const EXIT_ALERT: u8 = 5;
+
+#[derive(Debug, Error)]
+pub enum ClientError {
+    #[error("network error: {0}")]
+    Network(#[from] reqwest::Error),
+
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("parse error: {0}")]
+    Parse(#[from] serde_json::Error),
+
+    #[error("CSV error: {0}")]
+    Csv(#[from] csv::Error),
+
+    #[error("database error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+
+    #[error("result file {} is corrupt or in an unknown format: {reason}", path.display())]
+    CorruptResult { path: std::path::PathBuf, reason: String },
+
+    #[error("configuration error: {0}")]
+    Config(String),
+
+    /// `--preflight` could not get a price from the API before the run started.
+    #[error("preflight request to {url} failed: {source} (--no-preflight skips this check)")]
+    Preflight {
+        url: String,
+        #[source]
+        source: crate::source::FetchError,
+    },
+
+    /// `--mode=list-assets` could not fetch the list and had no cached copy to fall back on.
+    #[error("could not fetch the asset list from {url}: {source}")]
+    AssetList {
+        url: String,
+        #[source]
+        source: crate::source::FetchError,
+    },
+
+    /// A client hit an error no retry or later tick could fix, and stopped early.
+    #[error("price source failed for good: {0}")]
+    Unrecoverable(#[source] crate::source::FetchError),
+
+    /// `--fail-fast` stopped every client after this one's failed tick.
+    #[error("--fail-fast stopped the run: {0}")]
+    FailFast(String),
+
+    /// Nothing to report: no client got a price, or there is no recorded run to read.
+    #[error("no data: {0}")]
+    NoData(String),
+
+    /// `--alert-change` fired; the run itself succeeded and was recorded.
+    #[error("price moved {change_percent:+.2}% since the previous run, past the {threshold_percent}% alert threshold")]
+    PriceAlert { change_percent: f64, threshold_percent: f64 },
+
+    #[error("all {0} clients panicked, nothing to aggregate")]
+    AllClientsPanicked(usize),
+
+    #[error("client task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+}
+
+impl ClientError {
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            ClientError::Config(_) => EXIT_USAGE,
+            ClientError::NoData(_) => EXIT_NO_DATA,
+            ClientError::Network(_)
+            | ClientError::Preflight { .. }
+            | ClientError::AssetList { .. }
+            | ClientError::FailFast(_)
+            | ClientError::Unrecoverable(_) => EXIT_NETWORK,
+            ClientError::PriceAlert { .. } => EXIT_ALERT,
+            _ => EXIT_FAILURE,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::FetchError;
+    use reqwest::StatusCode;
+
+    #[test]
+    fn each_error_maps_to_its_documented_exit_code() {
+        let cases = [
+            (ClientError::Config("bad flag".to_string()), EXIT_USAGE),
+            (ClientError::NoData("no prices".to_string()), EXIT_NO_DATA),
+            (
+                ClientError::Preflight {
+                    url: "http://127.0.0.1:9".to_string(),
+                    source: FetchError::Status(StatusCode::BAD_GATEWAY),
+                },
+                EXIT_NETWORK,
+            ),
+            (
+                ClientError::AssetList {
+                    url: "http://127.0.0.1:9".to_string(),
+                    source: FetchError::Status(StatusCode::NOT_FOUND),
+                },
+                EXIT_NETWORK,
+            ),
+            (ClientError::FailFast("client 1 failed".to_string()), EXIT_NETWORK),
+            (ClientError::Unrecoverable(FetchError::Status(StatusCode::UNAUTHORIZED)), EXIT_NETWORK),
+            (
+                ClientError::PriceAlert {
+                    change_percent: 12.0,
+                    threshold_percent: 5.0,
+                },
+                EXIT_ALERT,
+            ),
+            (ClientError::AllClientsPanicked(3), EXIT_FAILURE),
+            (ClientError::Io(std::io::Error::other("disk full")), EXIT_FAILURE),
+        ];
+
+        for (error, code) in cases {
+            assert_eq!(error.exit_code(), code, "{}", error);
+        }
+    }
+
+    #[test]
+    fn clap_usage_errors_share_the_usage_exit_code() {
+        use clap::Parser;
+
+        let err = crate::cli::Cli::try_parse_from(["simulated_distributed_client", "--mode=nope"]).unwrap_err();
+
+        assert_eq!(err.exit_code(), i32::from(EXIT_USAGE));
+    }
+}
@@ -1,173 +1,1096 @@
-use serde::Deserialize;
-use std::fs::{File, OpenOptions};
-use std::io::{BufRead, BufReader, Write};
-use std::sync::{Arc, Mutex};
-use tokio::time::{Duration, Instant};
+mod aggregator;
+mod assets;
+mod backpressure;
+mod breaker;
+mod cache;
+mod cli;
+mod client;
+mod config;
+mod error;
+mod metrics;
+mod output;
+mod progress;
+mod record;
+mod server;
+mod source;
+mod stats;
+mod store;
+#[cfg(test)]
+mod testing;
+mod websocket;
 
-#[derive(Debug, Deserialize)]
-struct CoinbaseResponse {
-    data: Data,
-}
+use aggregator::{AggregateMethod, AggregationConfig, AggregatorData, ClientMessage};
+use assets::Asset;
+use clap::Parser;
+use cli::{validate_asset, Cli, Mode};
+use client::{build_http_client, http_client, preflight, FailFast, simulate_client, ClientConfig, SharedState, SyntheticConfig, COINBASE_WS_FEED};
+use error::ClientError;
+use futures_util::future::join_all;
+use output::{check_price_alert, fill_missing_aggregate, persist_record, print_event, read_result_file, Console, Event, OutputConfig, PriceAlert};
+use progress::Progress;
+use record::{format_percentiles, format_price, format_stat, parse_records, CsvRow, ResultRecord, CSV_HEADER, MARKDOWN_HEADING};
+use source::ApiCredentials;
+use stats::HistoryStats;
+use std::ffi::OsString;
+use std::io::{Read, Seek, SeekFrom};
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use metrics::Metrics;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, info_span, warn, Instrument};
+use tracing_subscriber::EnvFilter;
 
-#[derive(Debug, Deserialize)]
-struct Data {
-    amount: String,
+/// Maps the run's outcome to the exit codes in `error`, listed in `--help`.
+#[tokio::main]
+async fn main() -> ExitCode {
+    match run().await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::from(e.exit_code())
+        }
+    }
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args: Vec<String> = std::env::args().collect();
+async fn run() -> Result<(), ClientError> {
+    let args = config::layered_args(std::env::args_os().collect())?;
+    let cli = Cli::parse_from(&args);
+    init_tracing(&cli.log_filter())?;
 
-    if args.len() < 2 {
-        print_usage();
-        return Ok(());
+    match cli.mode {
+        Mode::Cache => cache_mode(&cli, &args).await?,
+        Mode::Read => {
+            info!("Selected mode: Read");
+            if let Some(db) = cli.db_path() {
+                print_records(&store::recent_runs(&db, store::RECENT_RUNS)?, cli.trend)?;
+                return Ok(());
+            }
+            let input = cli.input.clone().unwrap_or_else(|| PathBuf::from(cli.format.default_path()));
+            match read_mode(&input, cli.trend).await {
+                // --follow waits for the first run instead
+                Err(ClientError::NoData(reason)) if cli.follow => warn!("{}; waiting for new runs", reason),
+                result => result?,
+            }
+            if cli.follow {
+                follow_results(&input).await?;
+            }
+        }
+        Mode::Stats => {
+            info!("Selected mode: Stats");
+            let records = match cli.db_path() {
+                Some(db) => store::all_runs(&db)?,
+                None => {
+                    let input = cli.input.clone().unwrap_or_else(|| PathBuf::from(cli.format.default_path()));
+                    load_history(&input).await?
+                }
+            };
+            if records.is_empty() {
+                return Err(no_runs_recorded());
+            }
+            print_stats(&HistoryStats::from_records(&records), cli.console());
+        }
+        Mode::Serve => {
+            info!("Selected mode: Serve");
+            let input = cli.input.clone().unwrap_or_else(|| PathBuf::from(cli.format.default_path()));
+            server::serve(cli.addr, input).await?;
+        }
+        Mode::ListAssets => {
+            info!("Selected mode: ListAssets");
+            let client = http_client(Duration::from_secs(cli.timeout), cli.proxy().as_deref(), &cli.headers)?;
+            let assets = assets::list_assets(&client, &cli.endpoint).await?;
+            print_assets(&assets, cli.console());
+            info!("--asset also has to be one of --allowed-assets ({})", cli.allowed_assets.join(","));
+        }
     }
 
-    match args[1].as_str() {
-        "--mode=cache" => {
-            println!("Selected mode: Cache");
-            if args.len() >= 1 && args[2].starts_with("--times=") {
-                let times: u64 = args[2].split('=').nth(1).and_then(|s| s.parse().ok()).unwrap_or(10);
-                simulate_distributed_client(times).await?;
-            } else {
-                println!("Invalid argument for cache mode. Use --times=<seconds>.");
+    Ok(())
+}
+
+/// Polls with simulated clients, or generates `--dry-run` prices, then prints, records and
+/// checks the aggregate. `args` are the effective arguments, recorded with the run.
+async fn cache_mode(cli: &Cli, args: &[OsString]) -> Result<(), ClientError> {
+    info!("Selected mode: Cache");
+    // One seed drives --dry-run prices, --jitter offsets and --clients-vary settings so a
+    // run can be replayed
+    let seed = cli.seed.unwrap_or_else(rand::random);
+    if cli.jitter {
+        info!("Jittering client start offsets with seed {}", seed);
+    }
+    if cli.clients_vary {
+        info!("Varying client intervals and timeouts with seed {}", seed);
+    }
+    let times = cli.times_or_default();
+    let currencies = cli.currencies();
+    if currencies.len() > 1 {
+        if cli.compare.is_some() {
+            return Err(ClientError::Config("--compare takes a single --currency".to_string()));
+        }
+        if cli.metrics_addr.is_some() {
+            return Err(ClientError::Config(
+                "--metrics-addr takes a single --currency, each pool would serve its own".to_string(),
+            ));
+        }
+    }
+    let config = ClientConfig {
+        base_url: cli.endpoint.clone(),
+        times,
+        once: cli.once,
+        max_samples: cli.max_samples,
+        asset: validate_asset(&cli.asset, &cli.allowed_assets)?,
+        currency: currencies[0].clone(),
+        timeout: Duration::from_secs(cli.timeout),
+        total_timeout: cli.timeout_total(times),
+        proxy: cli.proxy(),
+        credentials: ApiCredentials::from_env()?,
+        headers: cli.headers.clone(),
+        interval: Duration::from_millis(cli.interval),
+        warmup: Duration::from_secs(cli.warmup),
+        averaging: cli.averaging(),
+        window: cli.window.map(Duration::from_secs),
+        transport: cli.transport.into(),
+        ws_url: COINBASE_WS_FEED.to_string(),
+        parser: cli.parser.into(),
+        fallback_url: cli.fallback_url.clone(),
+        fallback_parser: cli.fallback_parser.into(),
+        compare_sources: cli.compare_sources(),
+        max_concurrent: cli.max_concurrent.map(|n| n as usize),
+        rate: cli.rate.and_then(NonZeroU32::new),
+        cache_ttl: cli.shared_cache.then(|| Duration::from_millis(cli.cache_ttl)),
+        breaker_threshold: cli.breaker_threshold,
+        breaker_cooldown: Duration::from_secs(cli.breaker_cooldown),
+        jitter_seed: cli.jitter.then_some(seed),
+        vary_seed: cli.clients_vary.then_some(seed),
+        synthetic: cli.dry_run.then(|| {
+            info!("Dry run: generating synthetic prices around {} with seed {}", cli.base_price, seed);
+            SyntheticConfig {
+                base_price: cli.base_price,
+                seed,
             }
+        }),
+        fail_fast: cli.fail_fast.then(FailFast::default),
+        samples: cli.sample_output(),
+        precision: cli.output_precision,
+    };
+    let output = OutputConfig {
+        path: cli.output.clone().unwrap_or_else(|| PathBuf::from(cli.format.default_path())),
+        format: cli.format,
+        append: cli.append,
+        no_file: cli.no_file,
+        precision: cli.output_precision,
+        console: cli.console(),
+        progress: matches!(cli.console(), Console::Human | Console::Markdown),
+        metrics_addr: cli.metrics_addr,
+        db: cli.db_path(),
+    };
+    let aggregation = AggregationConfig {
+        method: cli.aggregate_method(),
+        reject_outliers: cli.reject_outliers,
+        report_order_seed: cli.seed,
+        min_price: cli.min_price,
+        max_price: cli.max_price,
+        channel_capacity: cli.channel_capacity,
+    };
+    // reqwest::Client is an Arc internally, so cloning it per task shares the pool
+    let http_client = build_http_client(&config)?;
+    if cli.preflight() {
+        preflight(&config, &http_client).await?;
+    }
+    let mut record = match &cli.compare {
+        Some((base, quote)) => {
+            let base = ClientConfig {
+                asset: validate_asset(base, &cli.allowed_assets)?,
+                ..config.clone()
+            };
+            let quote = ClientConfig {
+                asset: validate_asset(quote, &cli.allowed_assets)?,
+                ..config.clone()
+            };
+            let pool_output = OutputConfig {
+                progress: false,
+                ..output.clone()
+            };
+            let base_span = info_span!("pool", asset = %base.asset);
+            let quote_span = info_span!("pool", asset = %quote.asset);
+            let (base_record, quote_record) = tokio::join!(
+                simulate_distributed_client(&base, cli.clients, &aggregation, &pool_output, &http_client)
+                    .instrument(base_span),
+                simulate_distributed_client(&quote, cli.clients, &aggregation, &pool_output, &http_client)
+                    .instrument(quote_span),
+            );
+            with_fallback_aggregate(base_record?, &output)
+                .await
+                .compare_with(with_fallback_aggregate(quote_record?, &output).await)
         }
-        "--mode=read" => {
-            println!("Selected mode: Read");
-            read_mode()?;
+        None if currencies.len() > 1 => {
+            let pools: Vec<ClientConfig> = currencies
+                .iter()
+                .map(|currency| ClientConfig {
+                    currency: currency.clone(),
+                    ..config.clone()
+                })
+                .collect();
+            let pool_output = OutputConfig {
+                progress: false,
+                ..output.clone()
+            };
+            let mut records = Vec::with_capacity(pools.len());
+            for record in join_all(pools.iter().map(|pool| {
+                simulate_distributed_client(pool, cli.clients, &aggregation, &pool_output, &http_client)
+                    .instrument(info_span!("pool", currency = %pool.currency))
+            }))
+            .await
+            {
+                records.push(with_fallback_aggregate(record?, &output).await);
+            }
+            let mut records = records.into_iter();
+            let first = records.next().expect("at least one currency");
+            first.with_currencies(records)
         }
-        _ => {
-            println!("Invalid mode. Use cache or read.");
-            print_usage();
+        None => {
+            with_fallback_aggregate(
+                simulate_distributed_client(&config, cli.clients, &aggregation, &output, &http_client).await?,
+                &output,
+            )
+            .await
         }
+    };
+    record.args = config::recorded_args(args);
+    if cli.reproducible() {
+        record.normalize_for_replay(times);
+    }
+    print_aggregate(&record, output.console, output.precision);
+    let alert = match cli.alert_change {
+        Some(threshold) => check_price_alert(&record, &output, threshold).await,
+        None => None,
+    };
+    persist_record(&record, &output).await?;
+    // Any pool's failure stopped them all, so the first reason is the run's
+    if let Some(reason) = config.fail_fast.as_ref().and_then(FailFast::reason) {
+        return Err(ClientError::FailFast(reason.to_string()));
+    }
+    if let Some(alert) = alert {
+        print_alert(&alert, output.console, output.precision);
+        return Err(alert.into_error());
+    }
+    if record.reporting_clients == 0 {
+        return Err(ClientError::NoData(format!(
+            "no client got a {}-{} price this run",
+            record.asset, record.currency
+        )));
     }
 
     Ok(())
 }
 
-fn print_usage() {
-    println!("Usage:");
-    println!("  ./simple --mode=<cache|read> [--times=<seconds>]");
+/// Logs go to stderr so stdout carries only the results scripts scrape.
+fn init_tracing(log_level: &str) -> Result<(), ClientError> {
+    let filter = EnvFilter::try_new(log_level)
+        .map_err(|e| ClientError::Config(format!("invalid --log-level '{}': {}", log_level, e)))?;
+
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(|| progress::LogWriter)
+        .init();
+
+    Ok(())
 }
 
-async fn simulate_distributed_client(times: u64) -> Result<(), Box<dyn std::error::Error>> {
+/// Runs every client to completion, or until Ctrl+C, and returns the run's record. Printing
+/// and persisting it is left to the caller.
+async fn simulate_distributed_client(
+    config: &ClientConfig,
+    clients: usize,
+    aggregation: &AggregationConfig,
+    output: &OutputConfig,
+    http_client: &reqwest::Client,
+) -> Result<ResultRecord, ClientError> {
+    if clients == 0 {
+        return Err(ClientError::Config("at least one client is required".to_string()));
+    }
+    if let (Some(min), Some(max)) = (aggregation.min_price, aggregation.max_price) {
+        if min > max {
+            return Err(ClientError::Config(format!(
+                "--min-price={} is above --max-price={}",
+                min, max
+            )));
+        }
+    }
+    if config.warmup.as_secs() >= config.times {
+        return Err(ClientError::Config(format!(
+            "--warmup={} leaves nothing of the {}s polling window to average",
+            config.warmup.as_secs(),
+            config.times
+        )));
+    }
+
     let start_time = Instant::now();
 
-    let shared_aggregator_data = Arc::new(Mutex::new(AggregatorData::new()));
+    let (tx, rx) = mpsc::channel(aggregation.channel_capacity.unwrap_or(clients));
+    let shutdown = match &config.fail_fast {
+        Some(fail_fast) => fail_fast.shutdown_token(),
+        None => CancellationToken::new(),
+    };
+    let shared = SharedState::new(config);
+    let metrics_shutdown = CancellationToken::new();
+    let metrics_server = match output.metrics_addr {
+        Some(addr) => {
+            Some(metrics::spawn_metrics_server(addr, shared.metrics.clone(), metrics_shutdown.clone()).await?)
+        }
+        None => None,
+    };
+
+    let aggregator = tokio::spawn(run_aggregator(
+        rx,
+        output.console,
+        shared.metrics.clone(),
+        aggregation.report_order_seed,
+    ));
 
-    let handles: Vec<_> = (1..=5)
+    let handles: Vec<_> = (1..=clients)
         .map(|i| {
-            let shared_aggregator_data_clone = shared_aggregator_data.clone();
-            tokio::spawn(simulate_client(i, times, start_time, shared_aggregator_data_clone))
+            // Carry the caller's span (the `--compare` pool, if any) into the task's logs
+            let handle = tokio::spawn(
+                simulate_client(
+                    i,
+                    config.clone(),
+                    http_client.clone(),
+                    start_time,
+                    tx.clone(),
+                    shutdown.clone(),
+                    shared.clone(),
+                )
+                .in_current_span(),
+            );
+            (i, handle)
         })
         .collect();
+    let abort_handles: Vec<_> = handles.iter().map(|(_, handle)| handle.abort_handle()).collect();
 
-    // Await the completion of all spawned threads
-    for handle in handles {
-        let _ = handle.await?;
-    }
-
-    let final_aggregate = shared_aggregator_data.lock().unwrap().calculate_final_aggregate();
-    println!("Aggregator: Final aggregate of USD prices of BTC is: {}", final_aggregate);
+    // Drop our own sender so the aggregator stops once every client is done
+    drop(tx);
 
-    write_final_aggregate_to_file(final_aggregate)?;
+    let progress = if output.progress && !config.once {
+        Progress::start(Duration::from_secs(config.times), start_time, shared.metrics.clone())
+    } else {
+        None
+    };
 
-    Ok(())
-}
+    let join_clients = join_clients(handles);
+    tokio::pin!(join_clients);
 
-fn write_final_aggregate_to_file(final_aggregate: f64) -> Result<(), Box<dyn std::error::Error>> {
-    let file_path = "result.txt";
+    let (partial, timed_out, joined) = tokio::select! {
+        joined = &mut join_clients => (false, false, joined),
+        _ = tokio::signal::ctrl_c() => {
+            warn!("Interrupt received, stopping clients and writing the partial aggregate...");
+            shutdown.cancel();
+            (true, false, join_clients.await)
+        }
+        _ = tokio::time::sleep_until(start_time + config.total_timeout) => {
+            warn!(
+                "Run exceeded --timeout-total of {}s, aborting the remaining clients...",
+                config.total_timeout.as_secs()
+            );
+            // Ask clients to stop and report first; only those that ignore it lose their samples
+            shutdown.cancel();
+            let joined = match tokio::time::timeout(ABORT_GRACE, &mut join_clients).await {
+                Ok(joined) => joined,
+                Err(_) => {
+                    for handle in &abort_handles {
+                        handle.abort();
+                    }
+                    join_clients.await
+                }
+            };
+            (false, true, joined)
+        }
+    };
+    if let Some(progress) = progress {
+        progress.finish().await;
+    }
+    let stalled_clients = shared.backpressure.stalls().len();
+    if stalled_clients > 0 {
+        warn!(
+            "{} of {} clients waited on a full aggregator channel (--channel-capacity); see the record",
+            stalled_clients, clients
+        );
+    }
+    let cache_stats = shared.price_cache.as_ref().map(|cache| cache.stats());
+    if let Some(stats) = cache_stats {
+        info!("Shared cache: {} hits, {} misses", stats.hits, stats.misses);
+    }
+    if joined.panicked.len() == clients {
+        return Err(ClientError::AllClientsPanicked(clients));
+    }
+    if !joined.panicked.is_empty() {
+        warn!(
+            "{} of {} clients panicked ({:?}), aggregating over the rest.",
+            joined.panicked.len(),
+            clients,
+            joined.panicked
+        );
+    }
+    if !joined.aborted.is_empty() {
+        warn!("Aborted clients {:?}, their samples are lost.", joined.aborted);
+    }
+    if !joined.failed.is_empty() {
+        warn!(
+            "Clients {:?} stopped early on an error; their samples up to then are included.",
+            joined.failed
+        );
+    }
 
-    let mut file = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open(file_path)?;
+    let aggregator_data = aggregator.await?;
+    metrics_shutdown.cancel();
+    if let Some(server) = metrics_server {
+        server.await?;
+    }
+    if partial {
+        warn!("Run was interrupted, the aggregate is partial.");
+    }
+    let trimmed = aggregation
+        .reject_outliers
+        .map(|max_std_devs| aggregator_data.calculate_trimmed_aggregate(max_std_devs));
 
-    writeln!(file, "Final aggregate of USD prices of BTC: {}", final_aggregate)?;
+    let mut record = ResultRecord::new(&aggregator_data, config, clients, start_time.elapsed());
+    record.aggregate_method = aggregator_data.resolve_method(aggregation.method);
+    record.aggregate = aggregator_data.aggregate_by(record.aggregate_method);
+    record.fail_fast = config.fail_fast.as_ref().and_then(FailFast::reason).map(str::to_string);
+    record.partial = partial || record.fail_fast.is_some();
+    record.timed_out = timed_out;
+    record.cache = cache_stats;
+    record.backpressure = shared.backpressure.stalls();
+    record.outlier_threshold = aggregation.reject_outliers;
+    record.trimmed_aggregate = trimmed.map(|t| t.value);
+    record.outliers_rejected = trimmed.map_or(0, |t| t.rejected);
+    if record.reporting_clients > 0 && aggregation.is_out_of_bounds(record.aggregate) {
+        warn!(
+            "Final aggregate {} is outside the expected range [{}, {}], the API may have glitched.",
+            record.aggregate,
+            format_stat(aggregation.min_price, None),
+            format_stat(aggregation.max_price, None)
+        );
+        record.suspicious = true;
+    }
 
-    Ok(())
+    Ok(record)
 }
 
-async fn simulate_client(
-    client_id: usize,
-    times: u64,
-    start_time: Instant,
-    shared_aggregator_data: Arc<Mutex<AggregatorData>>,
-) -> Result<(), Box<dyn std::error::Error + Send + 'static>> {
-    let url = "https://api.coinbase.com/v2/prices/spot?currency=USD";
-    let client = reqwest::Client::new();
+/// How long clients get to report after `--timeout-total` before they're aborted outright.
+const ABORT_GRACE: Duration = Duration::from_secs(1);
 
-    let mut sum = 0.0;
-    let mut count = 0;
+/// Client tasks that didn't finish normally, by client id.
+#[derive(Debug, Default)]
+struct JoinedClients {
+    panicked: Vec<usize>,
+    /// Cut off by `--timeout-total`.
+    aborted: Vec<usize>,
+    /// Stopped early with an error, after reporting whatever they had collected.
+    failed: Vec<usize>,
+}
 
-    while start_time.elapsed().as_secs() < times {
-        if let Ok(response) = client.get(url).send().await {
-            if let Ok(message) = response.json::<CoinbaseResponse>().await {
-                let amount = message.data.amount.parse::<f64>().unwrap_or(0.0);
-                sum += amount;
-                count += 1;
+/// Awaits every client task. A client that panicked or was aborted never sends its report, so
+/// the aggregate simply leaves it out.
+async fn join_clients(handles: Vec<(usize, JoinHandle<Result<(), ClientError>>)>) -> JoinedClients {
+    let mut joined = JoinedClients::default();
+    for (client_id, handle) in handles {
+        match handle.await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                error!("Client {} stopped early: {}", client_id, e);
+                joined.failed.push(client_id);
+            }
+            Err(e) if e.is_cancelled() => joined.aborted.push(client_id),
+            Err(e) => {
+                error!("Client {} panicked: {}", client_id, e);
+                joined.panicked.push(client_id);
             }
         }
+    }
+    joined
+}
 
-        // Introduce a delay between requests
-        tokio::time::sleep(Duration::from_secs(1)).await;
+/// A run where no client got a price falls back to the previous aggregate, see
+/// `fill_missing_aggregate`.
+async fn with_fallback_aggregate(mut record: ResultRecord, output: &OutputConfig) -> ResultRecord {
+    if record.reporting_clients == 0 {
+        fill_missing_aggregate(&mut record, output).await;
     }
+    record
+}
 
-    let average = sum / count as f64;
-    println!("Client {}: Average USD price of BTC is: {}", client_id, average);
+/// The end-of-run stdout report in the chosen console style.
+fn print_aggregate(record: &ResultRecord, console: Console, precision: usize) {
+    match console {
+        Console::Json => print_event(&Event::Aggregate(record)),
+        // The report covers the --compare quote run and the ratio itself
+        Console::Markdown => print!("{}", record.to_markdown(precision)),
+        Console::Quiet => {
+            print_final_aggregate(record, precision);
+            print_other_currencies(record, precision);
+            if let Some(quote) = &record.compared_with {
+                print_final_aggregate(quote, precision);
+            }
+        }
+        Console::Human => {
+            print_final_aggregate(record, precision);
+            if record.aggregate_method != AggregateMethod::Mean {
+                println!("Aggregator: Aggregate method: {}", record.aggregate_method);
+                println!("Aggregator: Mean: {}", format_stat(record.mean, Some(precision)));
+            }
+            println!(
+                "Aggregator: Sample-weighted aggregate: {}",
+                format_stat(record.weighted_aggregate, Some(precision))
+            );
+            if let Some(trimmed) = record.trimmed_aggregate {
+                println!(
+                    "Aggregator: Outlier-trimmed aggregate: {} ({} rejected)",
+                    format_price(trimmed, Some(precision)),
+                    record.outliers_rejected
+                );
+            }
+            println!("Aggregator: Median: {}", format_stat(record.median, Some(precision)));
+            println!("Aggregator: Min: {}", format_stat(record.min, Some(precision)));
+            println!("Aggregator: Max: {}", format_stat(record.max, Some(precision)));
+            println!("Aggregator: Std dev: {}", format_stat(record.std_dev, Some(precision)));
+            if let Some(percentiles) = &record.percentiles {
+                println!("Aggregator: {}", format_percentiles(percentiles, Some(precision)));
+            }
+            println!("Aggregator: Requests: {}", record.requests);
+            if let Some(spread) = &record.source_spread {
+                println!(
+                    "Aggregator: Source spread: max {:.2}%, {} of {} compared ticks over {}%",
+                    spread.max_spread_percent, spread.disagreements, spread.compared_ticks, spread.threshold_percent
+                );
+            }
+            println!(
+                "Aggregator: Collected {} samples over {:.0}s ({:.1} req/s)",
+                record.samples, record.duration_secs, record.requests_per_sec
+            );
+            print_other_currencies(record, precision);
+            if let Some(quote) = &record.compared_with {
+                print_aggregate(quote, console, precision);
+            }
+        }
+    }
+    if let (Console::Human | Console::Quiet, Some(ratio)) = (console, record.format_ratio()) {
+        println!("Aggregator: {}", ratio);
+    }
+}
 
-    // Send the average to the aggregator
-    shared_aggregator_data.lock().unwrap().add_average(average);
+fn print_final_aggregate(record: &ResultRecord, precision: usize) {
+    println!(
+        "Aggregator: Final aggregate of {} prices in {} is: {}",
+        record.asset,
+        record.currency,
+        record.headline(Some(precision))
+    );
+}
 
-    Ok(())
+/// Loud enough to stand out in a cron mail; `--json` gets an `alert` event instead.
+fn print_alert(alert: &PriceAlert, console: Console, precision: usize) {
+    match console {
+        Console::Json => print_event(&Event::Alert(alert)),
+        Console::Human | Console::Quiet | Console::Markdown => println!(
+            "ALERT: {}-{} moved {:+.2}% since the previous run ({} -> {}), past the {}% threshold",
+            alert.asset,
+            alert.currency,
+            alert.change_percent,
+            format_price(alert.previous, Some(precision)),
+            format_price(alert.current, Some(precision)),
+            alert.threshold_percent
+        ),
+    }
+}
+
+/// The `--currency` fan-out's other aggregates, one final line each like the main currency's.
+fn print_other_currencies(record: &ResultRecord, precision: usize) {
+    for (currency, aggregate) in record.other_currencies() {
+        println!(
+            "Aggregator: Final aggregate of {} prices in {} is: {}",
+            record.asset,
+            currency,
+            format_price(*aggregate, Some(precision))
+        );
+    }
 }
 
-fn read_mode() -> Result<(), Box<dyn std::error::Error>> {
-    let file_path = "result.txt";
+/// Owns the `AggregatorData` and drains client averages until all senders drop.
+async fn run_aggregator(
+    mut rx: mpsc::Receiver<ClientMessage>,
+    console: Console,
+    metrics: Arc<Metrics>,
+    order_seed: Option<u64>,
+) -> AggregatorData {
+    let mut aggregator_data = AggregatorData::new();
+
+    while let Some(message) = rx.recv().await {
+        if console == Console::Json {
+            print_event(&Event::client_finished(&message));
+        }
+        let reported = matches!(message, ClientMessage::Report(_));
+        aggregator_data.add_message(message);
+        if reported {
+            metrics.record_aggregate(aggregator_data.calculate_final_aggregate());
+        }
+    }
+
+    if let Some(seed) = order_seed {
+        aggregator_data.reorder(seed);
+    }
+
+    aggregator_data
+}
 
+async fn read_mode(file_path: &Path, show_trend: bool) -> Result<(), ClientError> {
     match std::fs::metadata(file_path) {
         Ok(metadata) => {
             if metadata.len() == 0 {
-                println!("The result.txt file is empty. Run in cache mode first.");
-            } else {
-                let file = File::open(file_path)?;
-                let reader = BufReader::new(file);
+                return Err(ClientError::NoData(format!(
+                    "{} is empty, run in cache mode first",
+                    file_path.display()
+                )));
+            }
+
+            let contents = read_result_file(file_path).await?;
+            let corrupt = |reason: String| ClientError::CorruptResult {
+                path: file_path.to_path_buf(),
+                reason,
+            };
+
+            if contents.starts_with(CSV_HEADER) {
+                return print_csv_results(&contents).map_err(|e| match e {
+                    ClientError::Csv(e) => corrupt(e.to_string()),
+                    other => other,
+                });
+            }
+
+            // `--format=markdown` reports are meant to be read as they are, too
+            if contents.starts_with(MARKDOWN_HEADING) {
+                print!("{}", contents);
+                return Ok(());
+            }
 
-                for line in reader.lines() {
-                    println!("{}", line?);
+            if contents.starts_with("Final aggregate of ") {
+                if is_legacy_text(&contents) {
+                    // Files written before the JSON format are a single plain text line
+                    warn!(
+                        "{} uses the deprecated plain-text format. Re-run cache mode to upgrade it.",
+                        file_path.display()
+                    );
                 }
+                // `--format=text` files are already the human-readable summary
+                print!("{}", contents);
+                return Ok(());
             }
 
-            Ok(())
+            let records = parse_records(&contents).map_err(|e| corrupt(e.to_string()))?;
+            print_records(&records, show_trend)
         }
+        Err(_) => Err(ClientError::NoData(format!(
+            "{} does not exist, run in cache mode first (runs with --no-file don't write one)",
+            file_path.display()
+        ))),
+    }
+}
+
+/// The JSON records in an appended result file; an empty file is just no history.
+async fn load_history(file_path: &Path) -> Result<Vec<ResultRecord>, ClientError> {
+    let contents = match read_result_file(file_path).await {
+        Ok(contents) => contents,
         Err(_) => {
-            println!("The result.txt file does not exist. Run in cache mode first.");
-            Ok(())
+            return Err(ClientError::NoData(format!(
+                "{} does not exist, run in cache mode first",
+                file_path.display()
+            )))
         }
+    };
+
+    parse_records(&contents).map_err(|e| ClientError::CorruptResult {
+        path: file_path.to_path_buf(),
+        reason: format!("stats needs a JSON history (--append): {}", e),
+    })
+}
+
+fn print_assets(assets: &[Asset], console: Console) {
+    if console == Console::Json {
+        match serde_json::to_string(assets) {
+            Ok(line) => println!("{}", line),
+            Err(e) => warn!("Could not serialize the asset list: {}", e),
+        }
+        return;
+    }
+
+    for asset in assets {
+        println!("{:<8} {}", asset.code, asset.name);
     }
 }
 
-#[derive(Debug)]
-struct AggregatorData {
-    averages: Vec<f64>,
+fn print_stats(stats: &[HistoryStats], console: Console) {
+    if console == Console::Json {
+        match serde_json::to_string(stats) {
+            Ok(line) => println!("{}", line),
+            Err(e) => warn!("Could not serialize the history stats: {}", e),
+        }
+        return;
+    }
+
+    for pair in stats {
+        print!("{}", pair);
+    }
 }
 
-impl AggregatorData {
-    fn new() -> Self {
-        AggregatorData { averages: Vec::new() }
+fn print_records(records: &[ResultRecord], show_trend: bool) -> Result<(), ClientError> {
+    if records.is_empty() {
+        return Err(no_runs_recorded());
+    }
+
+    for (i, record) in records.iter().enumerate() {
+        if records.len() > 1 {
+            println!("Run {}:", i + 1);
+        }
+        record.print_summary();
     }
+    print_last_change(records);
 
-    fn add_average(&mut self, average: f64) {
-        self.averages.push(average);
+    if show_trend {
+        print_trend(records);
     }
 
-    fn calculate_final_aggregate(&self) -> f64 {
-        if self.averages.is_empty() {
-            0.0
-        } else {
-            self.averages.iter().sum::<f64>() / self.averages.len() as f64
+    Ok(())
+}
+
+fn no_runs_recorded() -> ClientError {
+    ClientError::NoData("no runs recorded yet, run in cache mode first".to_string())
+}
+
+/// Pre-JSON files read "Final aggregate of USD prices of BTC: ..."; `--format=text` says "prices in".
+fn is_legacy_text(contents: &str) -> bool {
+    contents.starts_with("Final aggregate of ") && contents.lines().next().is_some_and(|l| l.contains(" prices of "))
+}
+
+fn print_csv_results(contents: &str) -> Result<(), ClientError> {
+    let mut reader = csv::Reader::from_reader(contents.as_bytes());
+
+    println!("{:<36} {:<6} {:<8} {:>16} {:>8} {:>8}", "timestamp", "asset", "currency", "aggregate", "clients", "samples");
+    for row in reader.deserialize::<CsvRow>() {
+        print_csv_row(&row?);
+    }
+
+    Ok(())
+}
+
+fn print_csv_row(row: &CsvRow) {
+    println!(
+        "{:<36} {:<6} {:<8} {:>16.2} {:>8} {:>8}",
+        row.timestamp, row.asset, row.currency, row.aggregate, row.clients, row.samples
+    );
+}
+
+/// How often `--follow` checks the result file for growth.
+const FOLLOW_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Polls the result file like `tail -f`, printing each record appended after startup, until Ctrl+C.
+async fn follow_results(file_path: &Path) -> Result<(), ClientError> {
+    info!("Following {} for new runs, press Ctrl+C to stop.", file_path.display());
+    let mut offset = std::fs::metadata(file_path).map(|m| m.len()).unwrap_or(0);
+    // Bytes after the last newline; a writer may be mid-line when we poll
+    let mut pending = String::new();
+
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(FOLLOW_POLL_INTERVAL) => {}
+            _ = tokio::signal::ctrl_c() => {
+                info!("Stopped following {}.", file_path.display());
+                return Ok(());
+            }
+        }
+
+        let len = match std::fs::metadata(file_path) {
+            Ok(metadata) => metadata.len(),
+            Err(_) => continue,
+        };
+        if len < offset {
+            // Replaced by a run without --append; start again from the top
+            info!("{} was truncated, reading it from the start.", file_path.display());
+            offset = 0;
+            pending.clear();
+        }
+        if len == offset {
+            continue;
+        }
+
+        let mut file = std::fs::File::open(file_path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut appended = String::new();
+        file.read_to_string(&mut appended)?;
+        offset += appended.len() as u64;
+        pending.push_str(&appended);
+
+        while let Some(newline) = pending.find('\n') {
+            let line: String = pending.drain(..=newline).collect();
+            print_followed_line(line.trim_end());
+        }
+    }
+}
+
+fn print_followed_line(line: &str) {
+    if line.is_empty() || line == CSV_HEADER {
+        return;
+    }
+
+    if let Ok(record) = serde_json::from_str::<ResultRecord>(line) {
+        record.print_summary();
+        return;
+    }
+
+    let mut reader = csv::ReaderBuilder::new().has_headers(false).from_reader(line.as_bytes());
+    match reader.deserialize::<CsvRow>().next() {
+        Some(Ok(row)) => print_csv_row(&row),
+        // Text summaries span several lines and are printed as they are
+        _ => println!("{}", line),
+    }
+}
+
+fn print_trend(records: &[ResultRecord]) {
+    match (records.first(), records.last()) {
+        (Some(first), Some(last)) if records.len() > 1 => {
+            let (change, percent) = change_between(first, last);
+            println!(
+                "Trend over {} runs: {} -> {} ({:+} / {:+.2}%)",
+                records.len(),
+                first.aggregate,
+                last.aggregate,
+                change,
+                percent
+            );
         }
+        _ => println!("Trend: not enough runs recorded yet."),
+    }
+}
+
+/// How far the latest run moved from the one before it; a single run just shows its value.
+fn print_last_change(records: &[ResultRecord]) {
+    match records {
+        [.., previous, latest] => {
+            let (change, percent) = change_between(previous, latest);
+            println!(
+                "Change since previous run: {} -> {} ({:+} / {:+.2}%)",
+                previous.aggregate, latest.aggregate, change, percent
+            );
+        }
+        [latest] => println!("Latest aggregate: {}", latest.aggregate),
+        [] => {}
+    }
+}
+
+/// Absolute and percentage change of the aggregate from `from` to `to`.
+fn change_between(from: &ResultRecord, to: &ResultRecord) -> (f64, f64) {
+    let change = to.aggregate - from.aggregate;
+    let percent = if from.aggregate != 0.0 {
+        change / from.aggregate * 100.0
+    } else {
+        0.0
+    };
+    (change, percent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aggregator::ClientReport;
+    use crate::testing::{dry_run_config, file_output, test_config, MockResponse, MockServer, TempDir};
+
+    /// Prints only the aggregate line and persists nothing.
+    fn quiet_output() -> OutputConfig {
+        OutputConfig {
+            no_file: true,
+            ..file_output(PathBuf::from("result.txt"))
+        }
+    }
+
+    async fn run(config: ClientConfig, clients: usize, aggregation: AggregationConfig) -> Result<ResultRecord, ClientError> {
+        let http_client = build_http_client(&config).expect("test HTTP client builds");
+        simulate_distributed_client(&config, clients, &aggregation, &quiet_output(), &http_client).await
+    }
+
+    fn record_line(average: f64) -> String {
+        let mut data = AggregatorData::new();
+        data.add_report(ClientReport {
+            client_id: 1,
+            average,
+            samples: 1,
+            ..ClientReport::default()
+        });
+        let record = ResultRecord::new(&data, &test_config("http://127.0.0.1:9"), 1, Duration::from_secs(1));
+        serde_json::to_string(&record).expect("records serialize")
+    }
+
+    #[tokio::test]
+    async fn an_aggregator_that_hears_from_no_client_reports_zero_not_nan() {
+        let (tx, rx) = mpsc::channel(1);
+        drop(tx);
+
+        let data = run_aggregator(rx, Console::Quiet, Arc::new(Metrics::new()), None).await;
+
+        assert_eq!(data.calculate_final_aggregate(), 0.0);
+    }
+
+    #[tokio::test]
+    async fn read_mode_reads_a_recorded_run() {
+        let dir = TempDir::new();
+        let path = dir.file("result.txt", &record_line(100.0));
+
+        read_mode(&path, false).await.expect("a recorded run reads back");
+    }
+
+    #[tokio::test]
+    async fn read_mode_reports_no_data_for_a_missing_or_empty_file() {
+        let dir = TempDir::new();
+        let empty = dir.file("empty.txt", "");
+        let missing = empty.with_file_name("missing.txt");
+
+        for path in [&empty, &missing] {
+            let err = read_mode(path, false).await.unwrap_err();
+            assert!(matches!(err, ClientError::NoData(_)), "{}: {}", path.display(), err);
+        }
+    }
+
+    #[tokio::test]
+    async fn read_mode_reports_a_corrupt_file_as_such() {
+        let dir = TempDir::new();
+        let path = dir.file("result.txt", &record_line(100.0)[..20]);
+
+        let err = read_mode(&path, false).await.unwrap_err();
+
+        assert!(matches!(err, ClientError::CorruptResult { .. }), "{}", err);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn an_aggregate_outside_the_price_bounds_is_flagged_suspicious() {
+        let config = ClientConfig {
+            times: 2,
+            ..dry_run_config(42)
+        };
+        let within = AggregationConfig {
+            min_price: Some(50.0),
+            max_price: Some(150.0),
+            ..AggregationConfig::default()
+        };
+        let below_max = AggregationConfig {
+            max_price: Some(50.0),
+            ..AggregationConfig::default()
+        };
+
+        let record = run(config.clone(), 3, within).await.expect("dry runs succeed");
+        assert!(!record.suspicious);
+
+        let record = run(config, 3, below_max).await.expect("a suspicious run still succeeds");
+        assert!(record.suspicious);
+        assert!(record.aggregate > 50.0);
+    }
+
+    #[tokio::test]
+    async fn a_panicking_client_is_left_out_and_the_rest_are_aggregated() {
+        let (tx, rx) = mpsc::channel(3);
+        let aggregator = tokio::spawn(run_aggregator(rx, Console::Quiet, Arc::new(Metrics::new()), None));
+        let spawn_client = |client_id: usize, outcome: fn() -> Result<(), ClientError>| {
+            let tx = tx.clone();
+            let handle = tokio::spawn(async move {
+                let result = outcome();
+                let report = ClientReport {
+                    client_id,
+                    average: 100.0 * client_id as f64,
+                    samples: 1,
+                    ..ClientReport::default()
+                };
+                tx.send(ClientMessage::Report(report)).await.expect("aggregator is running");
+                result
+            });
+            (client_id, handle)
+        };
+        let handles = vec![
+            spawn_client(1, || Ok(())),
+            spawn_client(2, || panic!("forced panic")),
+            spawn_client(3, || Err(ClientError::Config("stopped early".to_string()))),
+        ];
+        drop(tx);
+
+        let joined = join_clients(handles).await;
+        let data = aggregator.await.expect("aggregator finishes");
+
+        assert_eq!(joined.panicked, vec![2]);
+        assert_eq!(joined.failed, vec![3]);
+        assert!(joined.aborted.is_empty());
+        assert_eq!(data.len(), 2);
+        assert_eq!(data.calculate_final_aggregate(), 200.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_total_timeout_stops_the_run_and_keeps_its_samples() {
+        let config = ClientConfig {
+            times: 60,
+            interval: Duration::from_secs(1),
+            total_timeout: Duration::from_secs(3),
+            ..dry_run_config(42)
+        };
+
+        let record = run(config, 2, AggregationConfig::default()).await.expect("a timed-out run still succeeds");
+
+        assert!(record.timed_out);
+        assert_eq!(record.reporting_clients, 2);
+        assert!(record.duration_secs < 5.0, "ran for {}s", record.duration_secs);
+    }
+
+    #[tokio::test]
+    async fn fail_fast_stops_every_client_on_the_first_failed_tick() {
+        let server = MockServer::start(vec![MockResponse::status(404)]).await;
+        let config = ClientConfig {
+            times: 10,
+            fail_fast: Some(FailFast::default()),
+            ..test_config(&server.url())
+        };
+        let start = Instant::now();
+
+        let record = run(config, 3, AggregationConfig::default()).await.expect("the run still records");
+
+        assert!(record.fail_fast.as_deref().is_some_and(|reason| reason.contains("404")), "{:?}", record.fail_fast);
+        assert!(record.partial);
+        assert_eq!(record.reporting_clients, 0);
+        assert!(server.requests() <= 3, "clients kept polling: {} requests", server.requests());
+        assert!(start.elapsed() < Duration::from_secs(2), "took {:?}", start.elapsed());
+    }
+
+    /// `testdata/dry_run_seed_42.txt` is what these flags wrote when it was recorded, with the
+    /// output directory replaced by `{output_dir}`. When a change to the record is intended,
+    /// rerun the binary with the same flags, redo that replacement and review the diff.
+    #[tokio::test(start_paused = true)]
+    async fn a_seeded_dry_run_writes_the_golden_result_file() {
+        let dir = TempDir::new();
+        let output = dir.path().join("result.txt");
+        let args: Vec<OsString> = [
+            "simulated_distributed_client",
+            "--mode=cache",
+            "--dry-run",
+            "--seed=42",
+            "--times=3",
+            "--clients=3",
+            "--quiet",
+        ]
+        .into_iter()
+        .map(OsString::from)
+        .chain([OsString::from(format!("--output={}", output.display()))])
+        .collect();
+        let cli = Cli::parse_from(&args);
+
+        cache_mode(&cli, &args).await.expect("dry runs succeed");
+
+        let written = std::fs::read_to_string(&output).expect("the run wrote its result");
+        let written = written.replace(&dir.path().display().to_string(), "{output_dir}");
+        assert_eq!(written, include_str!("../testdata/dry_run_seed_42.txt"));
     }
 }
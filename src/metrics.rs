@@ -0,0 +1,115 @@
+use crate::aggregator::Outcome;
+use crate::error::ClientError;
+use axum::extract::State;
+use axum::http::{header, StatusCode};
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::Router;
+use prometheus::{
+    exponential_buckets, Encoder, Gauge, Histogram, HistogramOpts, IntCounter, IntCounterVec, Opts, Registry, TextEncoder,
+};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+/// Prometheus collectors for one cache run, updated by the clients and the aggregator.
+#[derive(Debug)]
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    samples: Histogram,
+    clients_reported: IntCounter,
+    aggregate: Gauge,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let requests = IntCounterVec::new(
+            Opts::new("price_requests_total", "Ticks by how their fetch ended"),
+            &["outcome"],
+        )
+        .expect("valid requests metric");
+        let samples = Histogram::with_opts(
+            HistogramOpts::new("price_samples", "Prices averaged by the clients")
+                .buckets(exponential_buckets(1.0, 2.0, 24).expect("valid price buckets")),
+        )
+        .expect("valid samples metric");
+        let clients_reported = IntCounter::new("clients_reported_total", "Clients that reported an average")
+            .expect("valid clients metric");
+        let aggregate = Gauge::new("aggregate_price", "Mean of the client averages reported so far")
+            .expect("valid aggregate metric");
+
+        let registry = Registry::new();
+        registry.register(Box::new(requests.clone())).expect("unique requests metric");
+        registry.register(Box::new(samples.clone())).expect("unique samples metric");
+        registry
+            .register(Box::new(clients_reported.clone()))
+            .expect("unique clients metric");
+        registry.register(Box::new(aggregate.clone())).expect("unique aggregate metric");
+
+        Metrics {
+            registry,
+            requests,
+            samples,
+            clients_reported,
+            aggregate,
+        }
+    }
+
+    pub fn record_request(&self, outcome: Outcome) {
+        self.requests.with_label_values(&[outcome.label()]).inc();
+    }
+
+    pub fn observe_sample(&self, price: f64) {
+        self.samples.observe(price);
+    }
+
+    /// Samples observed so far across all clients.
+    pub fn samples_collected(&self) -> u64 {
+        self.samples.get_sample_count()
+    }
+
+    pub fn record_aggregate(&self, aggregate: f64) {
+        self.clients_reported.inc();
+        self.aggregate.set(aggregate);
+    }
+
+    fn render(&self) -> Result<String, prometheus::Error> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8_lossy(&buffer).into_owned())
+    }
+}
+
+/// Binds `addr` and serves `GET /metrics` in the background until `shutdown` is cancelled.
+/// Binding happens up front so a taken port fails the run before any client starts.
+pub async fn spawn_metrics_server(
+    addr: SocketAddr,
+    metrics: Arc<Metrics>,
+    shutdown: CancellationToken,
+) -> Result<JoinHandle<()>, ClientError> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    info!("Serving metrics on http://{}/metrics", listener.local_addr()?);
+
+    let app = Router::new().route("/metrics", get(metrics_handler)).with_state(metrics);
+
+    Ok(tokio::spawn(async move {
+        let server = axum::serve(listener, app).with_graceful_shutdown(shutdown.cancelled_owned());
+        if let Err(e) = server.await {
+            warn!("Metrics server stopped: {}", e);
+        }
+    }))
+}
+
+async fn metrics_handler(State(metrics): State<Arc<Metrics>>) -> impl IntoResponse {
+    match metrics.render() {
+        Ok(body) => (StatusCode::OK, [(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            [(header::CONTENT_TYPE, "text/plain")],
+            format!("could not encode metrics: {}", e),
+        ),
+    }
+}
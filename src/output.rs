@@ -0,0 +1,589 @@
+use crate::aggregator::{ClientMessage, RequestStats};
+use crate::cli::OutputFormat;
+use crate::error::ClientError;
+use crate::record::{parse_records, CsvRow, ResultRecord};
+use crate::store;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Tries at reading, replacing or appending to a result file another process has locked, the
+/// first retry after `LOCK_BACKOFF` and each one after that waiting twice as long.
+const LOCK_ATTEMPTS: u32 = 5;
+const LOCK_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Where and how a cache run persists its `ResultRecord`, and how much it prints.
+#[derive(Debug, Clone)]
+pub struct OutputConfig {
+    pub path: PathBuf,
+    pub format: OutputFormat,
+    pub append: bool,
+    /// `--no-file`: the record is printed but never persisted.
+    pub no_file: bool,
+    /// Decimal places for prices in the console lines and `--format=text` files.
+    pub precision: usize,
+    pub console: Console,
+    /// Draw the live progress bar; only one run at a time can own the terminal.
+    pub progress: bool,
+    /// Serve Prometheus metrics here while the run is in progress.
+    pub metrics_addr: Option<SocketAddr>,
+    /// With `--store=sqlite`, runs go to this database instead of `path`.
+    pub db: Option<PathBuf>,
+}
+
+/// What a cache run prints to stdout while and after it runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Console {
+    /// The `Aggregator: ...` lines.
+    Human,
+    /// Only the final aggregate line.
+    Quiet,
+    /// One `Event` per line, for piping into other tools.
+    Json,
+    /// The `--format=markdown` report, ready to paste.
+    Markdown,
+}
+
+/// A `--json` stdout line; `type` names the event.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event<'a> {
+    ClientFinished {
+        client_id: usize,
+        /// `None` when the client never got a price.
+        average: Option<f64>,
+        samples: usize,
+        requests: RequestStats,
+    },
+    /// One price as a client recorded it, under `--verbose`.
+    Sample {
+        client_id: usize,
+        price: f64,
+        /// Traded volume, when the source reports one.
+        volume: Option<f64>,
+        source: &'a str,
+        at: DateTime<Utc>,
+        /// Fetched during `--warmup`, so left out of the average.
+        warmup: bool,
+    },
+    Aggregate(&'a ResultRecord),
+    /// `--alert-change` fired for this run.
+    Alert(&'a PriceAlert),
+}
+
+/// A move past `--alert-change` between the previous run for a pair and this one.
+#[derive(Debug, Clone, Serialize)]
+pub struct PriceAlert {
+    pub asset: String,
+    pub currency: String,
+    pub previous: f64,
+    pub current: f64,
+    pub change_percent: f64,
+    pub threshold_percent: f64,
+}
+
+impl PriceAlert {
+    pub fn into_error(self) -> ClientError {
+        ClientError::PriceAlert {
+            change_percent: self.change_percent,
+            threshold_percent: self.threshold_percent,
+        }
+    }
+}
+
+impl Event<'_> {
+    pub fn client_finished(message: &ClientMessage) -> Event<'static> {
+        match message {
+            ClientMessage::Report(report) => Event::ClientFinished {
+                client_id: report.client_id,
+                average: Some(report.average),
+                samples: report.samples,
+                requests: report.requests,
+            },
+            ClientMessage::NoSamples { client_id, requests } => Event::ClientFinished {
+                client_id: *client_id,
+                average: None,
+                samples: 0,
+                requests: *requests,
+            },
+        }
+    }
+}
+
+/// Prints one `--json` event line; a serialization failure is logged rather than aborting the run.
+pub fn print_event(event: &Event) {
+    match serde_json::to_string(event) {
+        Ok(line) => println!("{}", line),
+        Err(e) => warn!("Could not serialize {:?}: {}", event, e),
+    }
+}
+
+/// Persists the record to the SQLite history when one is configured, else to the result file.
+pub async fn persist_record(record: &ResultRecord, output: &OutputConfig) -> Result<(), ClientError> {
+    if output.no_file {
+        info!("--no-file given, not writing {}", output.path.display());
+        return Ok(());
+    }
+
+    match &output.db {
+        Some(db) => store::insert_run(db, record),
+        None => write_final_aggregate_to_file(record, output).await,
+    }
+}
+
+/// For a run where no client got a price: reuse the last good aggregate for the same pair from
+/// where this run would be written, so a network outage doesn't overwrite it with 0.0. Without
+/// one, the record is flagged `no_data`. `--format=text` files hold no parseable history.
+pub async fn fill_missing_aggregate(record: &mut ResultRecord, output: &OutputConfig) {
+    match previous_aggregate(record, output).await {
+        Some(previous) => {
+            warn!("No samples collected, reusing the previous aggregate {} (stale)", previous);
+            record.aggregate = previous;
+            record.stale = true;
+        }
+        None => {
+            warn!("No samples collected and no previous aggregate to fall back on, recording no data");
+            record.no_data = true;
+        }
+    }
+}
+
+/// Compares a fresh run to the previous one for its pair, from where this run will be written,
+/// so it must be called before `persist_record`. Stale or empty runs have nothing to compare.
+pub async fn check_price_alert(
+    record: &ResultRecord,
+    output: &OutputConfig,
+    threshold_percent: f64,
+) -> Option<PriceAlert> {
+    if record.no_data || record.stale {
+        return None;
+    }
+    let previous = previous_aggregate(record, output).await?;
+    let change_percent = change_exceeding(previous, record.aggregate, threshold_percent)?;
+
+    Some(PriceAlert {
+        asset: record.asset.clone(),
+        currency: record.currency.clone(),
+        previous,
+        current: record.aggregate,
+        change_percent,
+        threshold_percent,
+    })
+}
+
+/// The signed percentage change from `previous` to `current` when its size is strictly over
+/// `threshold_percent`; a zero `previous` has no meaningful percentage and never alerts.
+pub fn change_exceeding(previous: f64, current: f64, threshold_percent: f64) -> Option<f64> {
+    if previous == 0.0 {
+        return None;
+    }
+    let change_percent = (current - previous) / previous * 100.0;
+
+    (change_percent.abs() > threshold_percent).then_some(change_percent)
+}
+
+async fn previous_aggregate(record: &ResultRecord, output: &OutputConfig) -> Option<f64> {
+    let same_pair = |asset: &str, currency: &str| asset == record.asset && currency == record.currency;
+
+    if let Some(db) = &output.db {
+        return store::recent_runs(db, store::RECENT_RUNS)
+            .ok()?
+            .into_iter()
+            .rev()
+            .find(|previous| !previous.no_data && same_pair(&previous.asset, &previous.currency))
+            .map(|previous| previous.aggregate);
+    }
+
+    let contents = read_result_file(&output.path).await.ok()?;
+    match output.format {
+        OutputFormat::Json => parse_records(&contents)
+            .ok()?
+            .into_iter()
+            .rev()
+            .find(|previous| !previous.no_data && same_pair(&previous.asset, &previous.currency))
+            .map(|previous| previous.aggregate),
+        OutputFormat::Csv => csv::Reader::from_reader(contents.as_bytes())
+            .deserialize::<CsvRow>()
+            .filter_map(Result::ok)
+            .filter(|row| row.aggregate != 0.0 && same_pair(&row.asset, &row.currency))
+            .last()
+            .map(|row| row.aggregate),
+        OutputFormat::Text | OutputFormat::Markdown => None,
+    }
+}
+
+/// Writes the record in the chosen format. JSON is a single line per run, so with `append`
+/// the result file becomes newline-delimited JSON; without it the file is replaced. CSV always
+/// appends a row, writing the header only when the file is new. Markdown reports are appended
+/// after a blank line. Only text and Markdown are rounded to `--output-precision`; JSON and CSV
+/// keep every digit for the tools that read them back.
+///
+/// A replaced file goes through `write_atomically` and an appended one through
+/// `append_result_file`, so a read or serve run looking at it mid-write never sees part of a run.
+async fn write_final_aggregate_to_file(record: &ResultRecord, output: &OutputConfig) -> Result<(), ClientError> {
+    let file_path = output.path.as_path();
+    create_parent_dirs(file_path)?;
+
+    match output.format {
+        OutputFormat::Csv => append_result_file(file_path, |has_rows| csv_row(record, !has_rows)).await,
+        OutputFormat::Json => {
+            let line = format!("{}\n", serde_json::to_string(record)?);
+            write_result_file(file_path, line.as_bytes(), output.append).await
+        }
+        OutputFormat::Text => {
+            let summary = format!("{:.*}", output.precision, record);
+            write_result_file(file_path, summary.as_bytes(), output.append).await
+        }
+        OutputFormat::Markdown => {
+            let report = record.to_markdown(output.precision);
+            if !output.append {
+                return Ok(write_atomically(file_path, report.as_bytes()).await?);
+            }
+            append_result_file(file_path, |follows_report| {
+                let separator = if follows_report { "\n" } else { "" };
+                Ok(format!("{}{}", separator, report).into_bytes())
+            })
+            .await
+        }
+    }
+}
+
+fn create_parent_dirs(file_path: &Path) -> Result<(), ClientError> {
+    match file_path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => std::fs::create_dir_all(parent).map_err(|e| {
+            ClientError::Io(io::Error::new(
+                e.kind(),
+                format!("could not create directory {}: {}", parent.display(), e),
+            ))
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Adds `contents` to the end of the file when `append`, else replaces the file with them.
+async fn write_result_file(file_path: &Path, contents: &[u8], append: bool) -> Result<(), ClientError> {
+    if append {
+        append_result_file(file_path, |_| Ok(contents.to_vec())).await
+    } else {
+        Ok(write_atomically(file_path, contents).await?)
+    }
+}
+
+/// Replaces `file_path` with `contents` whole: they go to a sibling temp file first, which is
+/// then renamed over it. A rename within one filesystem is atomic, so a concurrent reader sees
+/// either the old file or the new one, never a truncated or half-written one.
+pub async fn write_atomically(file_path: &Path, contents: &[u8]) -> io::Result<()> {
+    let temp_path = temp_path_for(file_path);
+    let replaced = match write_synced(&temp_path, contents) {
+        Ok(()) => retry_while_locked(file_path, || std::fs::rename(&temp_path, file_path)).await,
+        Err(e) => Err(e),
+    };
+    if replaced.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+
+    replaced
+}
+
+/// Extends the file in place with what `contents` gives, told whether the file already holds
+/// anything. The file is opened for appending and held under an exclusive lock from that check
+/// until the single write, so overlapping `--append` runs each add their run whole: neither
+/// loses the other's, and only the first to create a file gives it a CSV header. A reader
+/// only ever sees complete runs, and the cost doesn't grow with the history.
+async fn append_result_file(
+    file_path: &Path,
+    contents: impl FnOnce(bool) -> Result<Vec<u8>, ClientError>,
+) -> Result<(), ClientError> {
+    let mut file = retry_while_locked(file_path, || {
+        OpenOptions::new().create(true).append(true).open(file_path)
+    })
+    .await?;
+    lock_exclusive(&file, file_path).await?;
+
+    let has_contents = file.metadata()?.len() > 0;
+    file.write_all(&contents(has_contents)?)?;
+
+    Ok(())
+}
+
+fn csv_row(record: &ResultRecord, with_header: bool) -> Result<Vec<u8>, ClientError> {
+    let mut writer = csv::WriterBuilder::new().has_headers(with_header).from_writer(Vec::new());
+    writer.serialize(record.to_csv_row())?;
+
+    Ok(writer.into_inner().map_err(|e| e.into_error())?)
+}
+
+/// A result file's contents, waiting out another process that has it locked; read, serve and
+/// stats modes read through this too.
+pub async fn read_result_file(file_path: &Path) -> io::Result<String> {
+    retry_while_locked(file_path, || std::fs::read_to_string(file_path)).await
+}
+
+/// `.result.txt.tmp-<pid>` beside `result.txt`: the same directory keeps the rename on one
+/// filesystem, and the pid keeps two overlapping runs from sharing a temp file.
+fn temp_path_for(file_path: &Path) -> PathBuf {
+    let name = file_path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+    file_path.with_file_name(format!(".{}.tmp-{}", name, std::process::id()))
+}
+
+/// Flushed to disk before the rename, so a crash can't leave the renamed file empty.
+fn write_synced(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut file = std::fs::File::create(path)?;
+    file.write_all(contents)?;
+    file.sync_all()
+}
+
+/// Runs `operation` until it stops failing because another process has `file_path` locked,
+/// backing off between tries without holding up the runtime's other tasks. Once the lock
+/// outlasts `LOCK_ATTEMPTS`, the error says so.
+async fn retry_while_locked<T>(file_path: &Path, mut operation: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let mut attempt = 1;
+    let mut delay = LOCK_BACKOFF;
+    loop {
+        match operation() {
+            Err(e) if is_locked(&e) && attempt < LOCK_ATTEMPTS => {
+                warn!("{} is locked by another process, retrying in {:?}", file_path.display(), delay);
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+                delay *= 2;
+            }
+            Err(e) if is_locked(&e) => return Err(still_locked(file_path, e)),
+            result => return result,
+        }
+    }
+}
+
+/// Takes `file`'s exclusive lock, backing off like `retry_while_locked` while another run
+/// holds it for its own append. The lock goes when `file` is dropped.
+async fn lock_exclusive(file: &File, file_path: &Path) -> io::Result<()> {
+    let mut attempt = 1;
+    let mut delay = LOCK_BACKOFF;
+    loop {
+        match file.try_lock() {
+            Ok(()) => return Ok(()),
+            Err(TryLockError::WouldBlock) if attempt < LOCK_ATTEMPTS => {
+                warn!("{} is being appended to by another run, retrying in {:?}", file_path.display(), delay);
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+                delay *= 2;
+            }
+            Err(TryLockError::WouldBlock) => return Err(still_locked(file_path, TryLockError::WouldBlock.into())),
+            Err(TryLockError::Error(e)) => return Err(e),
+        }
+    }
+}
+
+fn still_locked(file_path: &Path, e: io::Error) -> io::Error {
+    io::Error::new(
+        e.kind(),
+        format!(
+            "{} is still locked by another process after {} attempts: {}",
+            file_path.display(),
+            LOCK_ATTEMPTS,
+            e
+        ),
+    )
+}
+
+/// Windows refuses to open or replace a file another process holds without sharing it, with a
+/// sharing or lock violation or, for a rename, access denied. Elsewhere files aren't locked
+/// that way, and a permission error is a real one that no retry fixes.
+fn is_locked(e: &io::Error) -> bool {
+    const ERROR_SHARING_VIOLATION: i32 = 32;
+    const ERROR_LOCK_VIOLATION: i32 = 33;
+
+    cfg!(windows)
+        && (e.kind() == io::ErrorKind::PermissionDenied
+            || matches!(e.raw_os_error(), Some(ERROR_SHARING_VIOLATION | ERROR_LOCK_VIOLATION)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aggregator::AggregatorData;
+    use crate::testing::{file_output, test_config, TempDir};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    fn record(asset: &str, aggregate: f64) -> ResultRecord {
+        let config = crate::client::ClientConfig {
+            asset: asset.to_string(),
+            ..test_config("http://127.0.0.1:9")
+        };
+        let mut record = ResultRecord::new(&AggregatorData::new(), &config, 1, Duration::from_secs(1));
+        record.aggregate = aggregate;
+        record
+    }
+
+    fn history(records: &[ResultRecord]) -> String {
+        records
+            .iter()
+            .map(|record| serde_json::to_string(record).expect("records serialize") + "\n")
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn a_run_without_samples_reuses_the_previous_aggregate_for_its_pair() {
+        let dir = TempDir::new();
+        let path = dir.file("result.txt", &history(&[record("BTC", 100.0), record("ETH", 5000.0)]));
+        let mut empty = record("BTC", 0.0);
+
+        fill_missing_aggregate(&mut empty, &file_output(path)).await;
+
+        assert!(empty.stale);
+        assert!(!empty.no_data);
+        assert_eq!(empty.aggregate, 100.0);
+    }
+
+    #[tokio::test]
+    async fn a_run_without_samples_or_history_is_no_data() {
+        let dir = TempDir::new();
+        let mut empty = record("BTC", 0.0);
+
+        fill_missing_aggregate(&mut empty, &file_output(dir.path().join("result.txt"))).await;
+
+        assert!(empty.no_data);
+        assert!(!empty.stale);
+        assert_eq!(empty.aggregate, 0.0);
+    }
+
+    #[tokio::test]
+    async fn a_change_past_the_threshold_alerts_and_exits_with_the_alert_code() {
+        let dir = TempDir::new();
+        let output = file_output(dir.file("result.txt", &history(&[record("BTC", 100.0)])));
+
+        let alert = check_price_alert(&record("BTC", 89.0), &output, 10.0).await.expect("an 11% drop alerts");
+
+        assert_eq!(alert.previous, 100.0);
+        assert_eq!(alert.current, 89.0);
+        assert!((alert.change_percent + 11.0).abs() < 1e-9, "got {}", alert.change_percent);
+        let error = alert.into_error();
+        assert_eq!(error.exit_code(), crate::error::EXIT_ALERT);
+        assert!(error.to_string().contains("-11.00%"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn no_alert_within_the_threshold_or_without_a_fresh_comparison() {
+        let dir = TempDir::new();
+        let output = file_output(dir.file("result.txt", &history(&[record("BTC", 100.0)])));
+        let mut stale = record("BTC", 200.0);
+        stale.stale = true;
+
+        let within = check_price_alert(&record("BTC", 110.0), &output, 10.0).await;
+        assert!(within.is_none(), "exactly 10% is not past 10%");
+        assert!(check_price_alert(&record("ETH", 200.0), &output, 10.0).await.is_none(), "no previous ETH run");
+        assert!(check_price_alert(&stale, &output, 10.0).await.is_none());
+    }
+
+    #[test]
+    fn change_exceeding_is_strict_and_ignores_a_zero_previous() {
+        assert_eq!(change_exceeding(100.0, 105.0, 4.0), Some(5.0));
+        assert_eq!(change_exceeding(100.0, 95.0, 4.0), Some(-5.0));
+        assert_eq!(change_exceeding(100.0, 105.0, 5.0), None);
+        assert_eq!(change_exceeding(0.0, 105.0, 5.0), None);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_reader_never_sees_a_half_written_result_file() {
+        let dir = TempDir::new();
+        let path = dir.path().join("result.txt");
+        // Large enough that a non-atomic write would be caught part way through
+        let versions = [vec![b'a'; 256 * 1024], vec![b'b'; 128 * 1024]];
+        write_atomically(&path, &versions[0]).await.expect("first write succeeds");
+        let writing = Arc::new(AtomicBool::new(true));
+
+        let reader = tokio::task::spawn_blocking({
+            let (path, versions, writing) = (path.clone(), versions.clone(), writing.clone());
+            move || {
+                let mut reads = 0;
+                while writing.load(Ordering::SeqCst) {
+                    let contents = std::fs::read(&path).expect("the file is always there");
+                    assert!(versions.contains(&contents), "read {} bytes of a torn file", contents.len());
+                    reads += 1;
+                }
+                reads
+            }
+        });
+        for round in 1..=200 {
+            write_atomically(&path, &versions[round % 2]).await.expect("write succeeds");
+        }
+        writing.store(false, Ordering::SeqCst);
+
+        assert!(reader.await.expect("the reader saw only whole files") > 0);
+        assert!(!temp_path_for(&path).exists(), "the temp file is renamed away");
+    }
+
+    /// Two writers each appending `runs` records to `output` at once, the first one's
+    /// aggregates counting up from 1 and the second's from 1001.
+    async fn append_from_two_writers(output: &OutputConfig, runs: usize) {
+        let writers = [0.0, 1000.0].map(|offset| {
+            let output = OutputConfig {
+                append: true,
+                ..output.clone()
+            };
+            tokio::spawn(async move {
+                for run in 1..=runs {
+                    persist_record(&record("BTC", offset + run as f64), &output).await.expect("append succeeds");
+                }
+            })
+        });
+        for writer in writers {
+            writer.await.expect("writer finishes");
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn overlapping_appends_keep_every_run() {
+        let dir = TempDir::new();
+        let output = file_output(dir.path().join("result.txt"));
+
+        append_from_two_writers(&output, 50).await;
+
+        let contents = std::fs::read_to_string(&output.path).expect("the history was written");
+        let mut aggregates: Vec<f64> = parse_records(&contents)
+            .expect("every line is a whole record")
+            .iter()
+            .map(|record| record.aggregate)
+            .collect();
+        aggregates.sort_by(f64::total_cmp);
+        let expected: Vec<f64> = (1..=50).chain(1001..=1050).map(f64::from).collect();
+        assert_eq!(aggregates, expected);
+    }
+
+    #[tokio::test]
+    async fn a_csv_append_waits_for_the_run_creating_the_file_and_skips_the_header() {
+        let dir = TempDir::new();
+        let output = OutputConfig {
+            format: OutputFormat::Csv,
+            ..file_output(dir.path().join("result.csv"))
+        };
+        // Another run that has just created the file and not yet written its header and row
+        let mut other_run = OpenOptions::new().create(true).append(true).open(&output.path).expect("file opens");
+        other_run.lock().expect("lock is free");
+
+        let append = tokio::spawn({
+            let output = output.clone();
+            async move { persist_record(&record("BTC", 2.0), &output).await }
+        });
+        tokio::time::sleep(LOCK_BACKOFF / 2).await;
+        assert_eq!(std::fs::metadata(&output.path).expect("file exists").len(), 0, "appended under the lock");
+        other_run
+            .write_all(&csv_row(&record("BTC", 1.0), true).expect("row serializes"))
+            .expect("other run writes");
+        drop(other_run);
+        append.await.expect("append finishes").expect("append succeeds");
+
+        let contents = std::fs::read_to_string(&output.path).expect("the history was written");
+        assert_eq!(contents.lines().filter(|line| line.starts_with("timestamp,")).count(), 1, "{:?}", contents);
+        let aggregates: Vec<f64> = csv::Reader::from_reader(contents.as_bytes())
+            .deserialize::<CsvRow>()
+            .map(|row| row.expect("rows parse").aggregate)
+            .collect();
+        assert_eq!(aggregates, vec![1.0, 2.0]);
+    }
+}
@@ -0,0 +1,80 @@
+use crate::metrics::Metrics;
+use indicatif::{ProgressBar, ProgressStyle};
+use std::io::{self, IsTerminal, Write};
+use std::sync::{Arc, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+/// The bar currently drawn on stderr, if any. Log lines are written around it rather than
+/// through it, so they never land in the middle of a redraw.
+static ACTIVE_BAR: Mutex<Option<ProgressBar>> = Mutex::new(None);
+
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// stderr for the tracing subscriber, hiding the progress bar while each log line is written.
+pub struct LogWriter;
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let active = ACTIVE_BAR.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        match active {
+            Some(bar) => bar.suspend(|| io::stderr().write_all(buf))?,
+            None => io::stderr().write_all(buf)?,
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
+
+/// An elapsed/total bar for the run's window, with a live count of samples across clients.
+pub struct Progress {
+    bar: ProgressBar,
+    stop: CancellationToken,
+    ticker: JoinHandle<()>,
+}
+
+impl Progress {
+    /// `None` when stderr isn't a terminal, since the bar would only be noise in a log file.
+    pub fn start(window: Duration, start_time: Instant, metrics: Arc<Metrics>) -> Option<Self> {
+        if !io::stderr().is_terminal() {
+            return None;
+        }
+
+        let bar = ProgressBar::new(window.as_secs());
+        bar.set_style(
+            ProgressStyle::with_template("[{elapsed_precise}] {bar:30} {pos}/{len}s {msg}")
+                .expect("valid progress template"),
+        );
+        *ACTIVE_BAR.lock().unwrap_or_else(|e| e.into_inner()) = Some(bar.clone());
+
+        let stop = CancellationToken::new();
+        let ticker = tokio::spawn({
+            let bar = bar.clone();
+            let stop = stop.clone();
+            async move {
+                let mut interval = tokio::time::interval(REFRESH_INTERVAL);
+                loop {
+                    tokio::select! {
+                        _ = interval.tick() => {}
+                        _ = stop.cancelled() => break,
+                    }
+                    bar.set_position(start_time.elapsed().as_secs().min(window.as_secs()));
+                    bar.set_message(format!("{} samples", metrics.samples_collected()));
+                }
+            }
+        });
+
+        Some(Progress { bar, stop, ticker })
+    }
+
+    pub async fn finish(self) {
+        self.stop.cancel();
+        let _ = self.ticker.await;
+        *ACTIVE_BAR.lock().unwrap_or_else(|e| e.into_inner()) = None;
+        self.bar.finish_and_clear();
+    }
+}
@@ -0,0 +1,566 @@
+use crate::aggregator::{AggregateMethod, AggregatorData, ClientReport, RequestStats, SamplePercentiles, SourceSpread};
+use crate::backpressure::Stall;
+use crate::cache::CacheStats;
+use crate::client::{Averaging, ClientConfig};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+use tokio::time::Duration;
+
+/// First line of every `--format=markdown` report, so read mode can tell one apart.
+pub const MARKDOWN_HEADING: &str = "## Price run: ";
+
+/// Version of the `result.txt` layout; bump whenever a field changes meaning or is removed.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// One cache run as persisted to `result.txt`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResultRecord {
+    pub schema_version: u32,
+    #[serde(default)]
+    pub timestamp: Option<DateTime<Utc>>,
+    /// Older records predate `--asset` and were always BTC.
+    #[serde(default = "default_asset")]
+    pub asset: String,
+    pub currency: String,
+    /// How each client averaged its own samples; older records always used the mean.
+    #[serde(default)]
+    pub averaging: Averaging,
+    /// `--window` in seconds, when clients averaged only their most recent samples.
+    #[serde(default)]
+    pub window_secs: Option<u64>,
+    /// How client averages combined into `aggregate`; older records always used the mean.
+    #[serde(default)]
+    pub aggregate_method: AggregateMethod,
+    pub aggregate: f64,
+    /// Plain mean of the client averages, whichever method produced `aggregate`.
+    #[serde(default)]
+    pub mean: Option<f64>,
+    #[serde(default)]
+    pub weighted_aggregate: Option<f64>,
+    /// `--reject-outliers` threshold in standard deviations, when one was given.
+    #[serde(default)]
+    pub outlier_threshold: Option<f64>,
+    #[serde(default)]
+    pub trimmed_aggregate: Option<f64>,
+    #[serde(default)]
+    pub outliers_rejected: usize,
+    pub median: Option<f64>,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub std_dev: Option<f64>,
+    /// Distribution of the raw samples across all clients.
+    #[serde(default)]
+    pub percentiles: Option<SamplePercentiles>,
+    pub clients: usize,
+    pub reporting_clients: usize,
+    /// Successful samples summed over all clients.
+    #[serde(default)]
+    pub samples: usize,
+    /// Tick outcomes summed over all clients.
+    #[serde(default)]
+    pub requests: RequestStats,
+    pub duration_secs: f64,
+    /// Successful samples per second of wall-clock run time, taken as at least one second.
+    #[serde(default)]
+    pub requests_per_sec: f64,
+    /// Each reporting client's average, ordered by client id.
+    #[serde(default)]
+    pub per_client: Vec<ClientReport>,
+    /// Set when the run was interrupted before the full window elapsed.
+    #[serde(default)]
+    pub partial: bool,
+    /// With `--fail-fast`, the failed tick that stopped every client; what they collected until
+    /// then is still aggregated.
+    #[serde(default)]
+    pub fail_fast: Option<String>,
+    /// Set when `--timeout-total` expired and unfinished clients were aborted.
+    #[serde(default)]
+    pub timed_out: bool,
+    /// Set when no client got a price and `aggregate` was carried over from the previous run.
+    #[serde(default)]
+    pub stale: bool,
+    /// Set when no client got a price and there was no earlier run to fall back on; `aggregate`
+    /// is then a placeholder 0.0, not a price.
+    #[serde(default)]
+    pub no_data: bool,
+    /// Set when the aggregate fell outside `--min-price`/`--max-price`.
+    #[serde(default)]
+    pub suspicious: bool,
+    /// Set for `--dry-run` records, whose prices were generated rather than fetched.
+    #[serde(default)]
+    pub synthetic: bool,
+    #[serde(default)]
+    pub seed: Option<u64>,
+    /// Machine the run happened on.
+    #[serde(default)]
+    pub hostname: Option<String>,
+    /// Crate version of the binary that produced the record.
+    #[serde(default)]
+    pub version: Option<String>,
+    /// Effective command line, config file values included, with secrets masked.
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// With `--compare`, the quote asset's run, made alongside this one.
+    #[serde(default)]
+    pub compared_with: Option<Box<ResultRecord>>,
+    /// This aggregate over `compared_with`'s, when both runs got a price.
+    #[serde(default)]
+    pub ratio: Option<f64>,
+    /// With several `--currency` codes, each one's aggregate, this record's own included.
+    /// Currencies whose pool got no price are left out.
+    #[serde(default)]
+    pub by_currency: BTreeMap<String, f64>,
+    /// `--shared-cache` hits and misses across every client.
+    #[serde(default)]
+    pub cache: Option<CacheStats>,
+    /// `--compare-sources` per-source averages and spread, over every client.
+    #[serde(default)]
+    pub source_spread: Option<SourceSpread>,
+    /// Clients that waited on a full aggregator channel, by client id.
+    #[serde(default)]
+    pub backpressure: BTreeMap<usize, Stall>,
+}
+
+impl ResultRecord {
+    pub fn new(aggregator_data: &AggregatorData, config: &ClientConfig, clients: usize, duration: Duration) -> Self {
+        ResultRecord {
+            schema_version: SCHEMA_VERSION,
+            timestamp: Some(Utc::now()),
+            asset: config.asset.clone(),
+            currency: config.currency.clone(),
+            averaging: config.averaging,
+            window_secs: config.window.map(|window| window.as_secs()),
+            aggregate_method: AggregateMethod::Mean,
+            aggregate: aggregator_data.calculate_final_aggregate(),
+            mean: (!aggregator_data.reports().is_empty()).then(|| aggregator_data.calculate_final_aggregate()),
+            weighted_aggregate: aggregator_data.calculate_weighted_aggregate(),
+            outlier_threshold: None,
+            trimmed_aggregate: None,
+            outliers_rejected: 0,
+            median: aggregator_data.median(),
+            min: aggregator_data.min(),
+            max: aggregator_data.max(),
+            std_dev: aggregator_data.std_dev(),
+            percentiles: aggregator_data.sample_percentiles(),
+            clients,
+            reporting_clients: aggregator_data.len(),
+            samples: aggregator_data.total_samples(),
+            requests: aggregator_data.requests(),
+            duration_secs: duration.as_secs_f64(),
+            requests_per_sec: rate(aggregator_data.total_samples(), duration),
+            per_client: sorted_reports(aggregator_data),
+            partial: false,
+            fail_fast: None,
+            timed_out: false,
+            stale: false,
+            no_data: false,
+            suspicious: false,
+            synthetic: config.synthetic.is_some(),
+            seed: config.synthetic.map(|synthetic| synthetic.seed),
+            hostname: Some(gethostname::gethostname().to_string_lossy().into_owned()),
+            version: Some(env!("CARGO_PKG_VERSION").to_string()),
+            args: Vec::new(),
+            compared_with: None,
+            ratio: None,
+            by_currency: BTreeMap::new(),
+            cache: None,
+            source_spread: aggregator_data.source_spread(),
+            backpressure: BTreeMap::new(),
+        }
+    }
+
+    /// The aggregate as shown to people: "no data" instead of a placeholder 0.0, and stale
+    /// values labelled as such.
+    pub fn headline(&self, precision: Option<usize>) -> String {
+        if self.no_data {
+            "no data".to_string()
+        } else if self.stale {
+            format!("{} (stale, reused previous run)", format_price(self.aggregate, precision))
+        } else {
+            format_price(self.aggregate, precision)
+        }
+    }
+
+    /// Drops or fixes every field that depends on the wall clock or the machine, so a seeded dry
+    /// run of `window_secs` writes the same bytes each time: no timestamps or hostname, and
+    /// durations and rates taken from the nominal window instead of measured.
+    pub fn normalize_for_replay(&mut self, window_secs: u64) {
+        let window = Duration::from_secs(window_secs);
+        self.timestamp = None;
+        self.hostname = None;
+        self.duration_secs = window.as_secs_f64();
+        self.requests_per_sec = rate(self.samples, window);
+        self.backpressure.clear();
+        for report in &mut self.per_client {
+            report.started_at = None;
+            report.finished_at = None;
+            report.duration_secs = 0.0;
+        }
+        if let Some(quote) = &mut self.compared_with {
+            quote.normalize_for_replay(window_secs);
+        }
+    }
+
+    /// Attaches the `--compare` quote asset's run and the base/quote price ratio.
+    pub fn compare_with(mut self, quote: ResultRecord) -> Self {
+        let has_price = |record: &ResultRecord| !record.no_data && record.aggregate != 0.0;
+        self.ratio = (has_price(&self) && has_price(&quote)).then(|| self.aggregate / quote.aggregate);
+        self.compared_with = Some(Box::new(quote));
+        self
+    }
+
+    /// Folds the other `--currency` pools' runs into `by_currency`. This record keeps the full
+    /// details of the first currency's run only.
+    pub fn with_currencies(mut self, others: impl IntoIterator<Item = ResultRecord>) -> Self {
+        let others: Vec<ResultRecord> = others.into_iter().collect();
+        self.by_currency = std::iter::once(&self)
+            .chain(&others)
+            .filter(|record| !record.no_data)
+            .map(|record| (record.currency.clone(), record.aggregate))
+            .collect();
+        self
+    }
+
+    /// `by_currency` without this record's own currency.
+    pub fn other_currencies(&self) -> impl Iterator<Item = (&String, &f64)> {
+        self.by_currency
+            .iter()
+            .filter(move |(currency, _)| **currency != self.currency)
+    }
+
+    pub fn format_ratio(&self) -> Option<String> {
+        let quote = self.compared_with.as_ref()?;
+        Some(format!(
+            "Ratio {}/{}: {}",
+            self.asset,
+            quote.asset,
+            format_stat(self.ratio, None)
+        ))
+    }
+
+    pub fn print_summary(&self) {
+        print!("{}", self);
+    }
+
+    /// The run as GitHub-flavored Markdown for pasting into issues and docs: a heading, a table
+    /// of the headline statistics and one of the client averages. A `--compare` quote run
+    /// follows as its own report.
+    pub fn to_markdown(&self, precision: usize) -> String {
+        let precision = Some(precision);
+        let mut report = String::new();
+        // Writing to a String can't fail
+        let _ = self.write_markdown(&mut report, precision);
+        report
+    }
+
+    fn write_markdown(&self, out: &mut String, precision: Option<usize>) -> fmt::Result {
+        writeln!(out, "{}{}-{}", MARKDOWN_HEADING, self.asset, self.currency)?;
+        writeln!(out)?;
+        writeln!(out, "| Statistic | Value |")?;
+        writeln!(out, "| --- | ---: |")?;
+        writeln!(out, "| Asset | {} |", self.asset)?;
+        writeln!(out, "| Currency | {} |", self.currency)?;
+        writeln!(out, "| Aggregate ({}) | {} |", self.aggregate_method, self.headline(precision))?;
+        writeln!(out, "| Median | {} |", format_stat(self.median, precision))?;
+        writeln!(out, "| Std dev | {} |", format_stat(self.std_dev, precision))?;
+        writeln!(out, "| Min | {} |", format_stat(self.min, precision))?;
+        writeln!(out, "| Max | {} |", format_stat(self.max, precision))?;
+        writeln!(out, "| Clients | {} ({} reporting) |", self.clients, self.reporting_clients)?;
+        writeln!(out, "| Samples | {} |", self.samples)?;
+        writeln!(out, "| Duration | {:.2}s |", self.duration_secs)?;
+        if let Some(timestamp) = self.timestamp {
+            writeln!(out, "| Recorded at | {} |", timestamp.to_rfc3339())?;
+        }
+        if self.partial {
+            writeln!(out, "| Partial run | interrupted before the window elapsed |")?;
+        }
+        if self.synthetic {
+            writeln!(out, "| Synthetic data | --dry-run, not real prices |")?;
+        }
+
+        if !self.per_client.is_empty() {
+            writeln!(out)?;
+            writeln!(out, "| Client | Average | Samples | Stopped by |")?;
+            writeln!(out, "| ---: | ---: | ---: | --- |")?;
+            for report in &self.per_client {
+                writeln!(
+                    out,
+                    "| {} | {} | {} | {} |",
+                    report.client_id,
+                    format_price(report.average, precision),
+                    report.samples,
+                    report.stopped_by
+                )?;
+            }
+        }
+
+        if !self.by_currency.is_empty() {
+            writeln!(out)?;
+            writeln!(out, "| Currency | Aggregate |")?;
+            writeln!(out, "| --- | ---: |")?;
+            for (currency, aggregate) in &self.by_currency {
+                writeln!(out, "| {} | {} |", currency, format_price(*aggregate, precision))?;
+            }
+        }
+
+        if let Some(quote) = &self.compared_with {
+            writeln!(out)?;
+            quote.write_markdown(out, precision)?;
+            if let Some(ratio) = self.format_ratio() {
+                writeln!(out)?;
+                writeln!(out, "{}", ratio)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn to_csv_row(&self) -> CsvRow {
+        CsvRow {
+            timestamp: self.timestamp.map(|t| t.to_rfc3339()).unwrap_or_default(),
+            asset: self.asset.clone(),
+            currency: self.currency.clone(),
+            aggregate: self.aggregate,
+            clients: self.clients,
+            samples: self.samples,
+        }
+    }
+}
+
+/// Human-readable summary, also used verbatim as the `--format=text` file contents. A
+/// precision, as in `{:.2}`, rounds every price to that many decimal places.
+impl fmt::Display for ResultRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let precision = f.precision();
+        writeln!(
+            f,
+            "Final aggregate of {} prices in {}: {}",
+            self.asset,
+            self.currency,
+            self.headline(precision)
+        )?;
+        match self.averaging {
+            Averaging::Mean => writeln!(f, "  Client averaging: mean")?,
+            Averaging::Ema { alpha } => writeln!(f, "  Client averaging: EMA (alpha {})", alpha)?,
+        }
+        if let Some(window) = self.window_secs {
+            writeln!(f, "  Client window: last {}s", window)?;
+        }
+        if self.aggregate_method != AggregateMethod::Mean {
+            writeln!(f, "  Aggregate method: {}", self.aggregate_method)?;
+            writeln!(f, "  Mean: {}", format_stat(self.mean, precision))?;
+        }
+        writeln!(f, "  Sample-weighted: {}", format_stat(self.weighted_aggregate, precision))?;
+        if let (Some(threshold), Some(trimmed)) = (self.outlier_threshold, self.trimmed_aggregate) {
+            writeln!(
+                f,
+                "  Outlier-trimmed (>{} std devs): {} ({} rejected)",
+                threshold,
+                format_price(trimmed, precision),
+                self.outliers_rejected
+            )?;
+        }
+        writeln!(f, "  Median: {}", format_stat(self.median, precision))?;
+        writeln!(f, "  Min: {}", format_stat(self.min, precision))?;
+        writeln!(f, "  Max: {}", format_stat(self.max, precision))?;
+        writeln!(f, "  Std dev: {}", format_stat(self.std_dev, precision))?;
+        if let Some(percentiles) = self.percentiles {
+            writeln!(f, "  {}", format_percentiles(&percentiles, precision))?;
+        }
+        writeln!(f, "  Clients: {} ({} reporting)", self.clients, self.reporting_clients)?;
+        writeln!(f, "  Samples: {}", self.samples)?;
+        writeln!(f, "  Requests: {}", self.requests)?;
+        if let Some(cache) = self.cache {
+            writeln!(f, "  Shared cache: {} hits, {} misses", cache.hits, cache.misses)?;
+        }
+        if !self.backpressure.is_empty() {
+            let stalls: Vec<String> = self
+                .backpressure
+                .iter()
+                .map(|(client_id, stall)| format!("client {} {:.3}s", client_id, stall.blocked_secs))
+                .collect();
+            writeln!(f, "  Blocked on the aggregator channel: {}", stalls.join(", "))?;
+        }
+        if let Some(spread) = &self.source_spread {
+            writeln!(
+                f,
+                "  Source spread: max {:.2}% over {} compared ticks, {} over the {}% threshold",
+                spread.max_spread_percent, spread.compared_ticks, spread.disagreements, spread.threshold_percent
+            )?;
+            for (source, average) in &spread.by_source {
+                writeln!(
+                    f,
+                    "    {}: {} ({} samples)",
+                    source,
+                    format_price(average.mean, precision),
+                    average.samples
+                )?;
+            }
+        }
+        if let Some(timestamp) = self.timestamp {
+            writeln!(f, "  Recorded at: {}", timestamp.to_rfc3339())?;
+        }
+        writeln!(f, "  Duration: {:.2}s ({:.1} req/s)", self.duration_secs, self.requests_per_sec)?;
+        if self.partial {
+            writeln!(f, "  Partial run: interrupted before the window elapsed")?;
+        }
+        if let Some(reason) = &self.fail_fast {
+            writeln!(f, "  Stopped by --fail-fast: {}", reason)?;
+        }
+        if self.timed_out {
+            writeln!(f, "  Timed out: --timeout-total expired, unfinished clients were aborted")?;
+        }
+        if self.suspicious {
+            writeln!(f, "  Suspicious: aggregate is outside the expected price range")?;
+        }
+        if self.synthetic {
+            writeln!(
+                f,
+                "  Synthetic data (--dry-run, seed {}): not real prices",
+                self.seed.map_or_else(|| "unknown".to_string(), |seed| seed.to_string())
+            )?;
+        }
+        writeln!(f, "  Schema version: {}", self.schema_version)?;
+        if let Some(hostname) = &self.hostname {
+            writeln!(f, "  Host: {}", hostname)?;
+        }
+        if let Some(version) = &self.version {
+            writeln!(f, "  Version: {}", version)?;
+        }
+        if !self.args.is_empty() {
+            writeln!(f, "  Args: {}", self.args.join(" "))?;
+        }
+        if !self.per_client.is_empty() {
+            writeln!(
+                f,
+                "  {:>6}  {:>16}  {:>7}  {:>9}  {:>8}  {:>11}  sources",
+                "client", "average", "samples", "duration", "interval", "stopped by"
+            )?;
+            for report in &self.per_client {
+                let sources: Vec<String> = report
+                    .samples_by_source
+                    .iter()
+                    .map(|(source, samples)| format!("{}={}", source, samples))
+                    .collect();
+                writeln!(
+                    f,
+                    "  {:>6}  {:>16.*}  {:>7}  {:>8.2}s  {:>6}ms  {:>11}  {}",
+                    report.client_id,
+                    precision.unwrap_or(2),
+                    report.average,
+                    report.samples,
+                    report.duration_secs,
+                    report.interval_ms,
+                    report.stopped_by.to_string(),
+                    sources.join(", ")
+                )?;
+                if let Some(error) = &report.error {
+                    writeln!(f, "  {:>6}  stopped early: {}", "", error)?;
+                }
+            }
+        }
+        if !self.by_currency.is_empty() {
+            writeln!(f, "  By currency:")?;
+            for (currency, aggregate) in &self.by_currency {
+                writeln!(f, "    {}: {}", currency, format_price(*aggregate, precision))?;
+            }
+        }
+        if let Some(quote) = &self.compared_with {
+            writeln!(f, "Compared with {}:", quote.asset)?;
+            match precision {
+                Some(precision) => write!(f, "{:.*}", precision, quote)?,
+                None => write!(f, "{}", quote)?,
+            }
+        }
+        if let Some(ratio) = self.format_ratio() {
+            writeln!(f, "{}", ratio)?;
+        }
+        Ok(())
+    }
+}
+
+/// One run as a `result.csv` row; field order is the column order.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CsvRow {
+    pub timestamp: String,
+    pub asset: String,
+    pub currency: String,
+    pub aggregate: f64,
+    pub clients: usize,
+    pub samples: usize,
+}
+
+pub const CSV_HEADER: &str = "timestamp,asset,currency,aggregate,clients,samples";
+
+/// Parses a JSON result file: a single record, or one per line when written with `--append`.
+pub fn parse_records(contents: &str) -> Result<Vec<ResultRecord>, serde_json::Error> {
+    contents
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(serde_json::from_str::<ResultRecord>)
+        .collect()
+}
+
+fn sorted_reports(aggregator_data: &AggregatorData) -> Vec<ClientReport> {
+    let mut reports = aggregator_data.reports().to_vec();
+    reports.sort_by_key(|report| report.client_id);
+    reports
+}
+
+/// Shortest span a rate is computed over. A `--once` or dry run can finish in microseconds, and
+/// dividing by that would report thousands of requests per second, or `inf` at zero; a run
+/// shorter than a second reports its samples as if they took one.
+const MIN_RATE_WINDOW: Duration = Duration::from_secs(1);
+
+fn rate(samples: usize, duration: Duration) -> f64 {
+    if samples == 0 {
+        return 0.0;
+    }
+
+    samples as f64 / duration.max(MIN_RATE_WINDOW).as_secs_f64()
+}
+
+fn default_asset() -> String {
+    "BTC".to_string()
+}
+
+pub fn format_percentiles(percentiles: &SamplePercentiles, precision: Option<usize>) -> String {
+    format!(
+        "Sample p50/p90/p99: {} / {} / {}",
+        format_price(percentiles.p50, precision),
+        format_price(percentiles.p90, precision),
+        format_price(percentiles.p99, precision)
+    )
+}
+
+pub fn format_stat(value: Option<f64>, precision: Option<usize>) -> String {
+    value.map_or_else(|| "n/a".to_string(), |v| format_price(v, precision))
+}
+
+/// A price rounded to `precision` decimal places; `None` keeps every digit.
+pub fn format_price(value: f64, precision: Option<usize>) -> String {
+    match precision {
+        Some(precision) => format!("{:.*}", precision, value),
+        None => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_very_short_run_is_rated_over_at_least_a_second() {
+        assert_eq!(rate(100, Duration::ZERO), 100.0);
+        assert_eq!(rate(100, Duration::from_micros(10)), 100.0);
+        assert_eq!(rate(100, Duration::from_millis(999)), 100.0);
+    }
+
+    #[test]
+    fn a_longer_run_is_rated_over_its_duration() {
+        assert_eq!(rate(10, Duration::from_secs(2)), 5.0);
+        assert_eq!(rate(0, Duration::from_secs(2)), 0.0);
+        assert_eq!(rate(0, Duration::ZERO), 0.0);
+    }
+}
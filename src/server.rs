@@ -0,0 +1,71 @@
+use crate::error::ClientError;
+use crate::record::{parse_records, ResultRecord};
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::routing::get;
+use axum::{Json, Router};
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tracing::{info, warn};
+
+#[derive(Clone)]
+struct AppState {
+    result_path: Arc<PathBuf>,
+}
+
+/// Serves `GET /aggregate` and `GET /health` until Ctrl+C.
+pub async fn serve(addr: SocketAddr, result_path: PathBuf) -> Result<(), ClientError> {
+    let app = Router::new()
+        .route("/health", get(health))
+        .route("/aggregate", get(aggregate))
+        .with_state(AppState {
+            result_path: Arc::new(result_path.clone()),
+        });
+
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    info!("Serving {} on http://{}", result_path.display(), listener.local_addr()?);
+
+    axum::serve(listener, app)
+        .with_graceful_shutdown(async {
+            let _ = tokio::signal::ctrl_c().await;
+            info!("Interrupt received, shutting down the server.");
+        })
+        .await?;
+
+    Ok(())
+}
+
+async fn health() -> StatusCode {
+    StatusCode::OK
+}
+
+/// The most recent record, re-read on every request so new cache runs show up immediately.
+async fn aggregate(State(state): State<AppState>) -> Result<Json<ResultRecord>, (StatusCode, String)> {
+    let path = state.result_path.as_path();
+
+    let contents = tokio::fs::read_to_string(path).await.map_err(|e| match e.kind() {
+        ErrorKind::NotFound => (
+            StatusCode::NOT_FOUND,
+            format!("{} does not exist; run cache mode first", path.display()),
+        ),
+        _ => {
+            warn!("Could not read {}: {}", path.display(), e);
+            (StatusCode::INTERNAL_SERVER_ERROR, format!("could not read {}", path.display()))
+        }
+    })?;
+
+    let mut records = parse_records(&contents).map_err(|e| {
+        warn!("{} is not a JSON result file: {}", path.display(), e);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("{} is not a JSON result file", path.display()),
+        )
+    })?;
+
+    records
+        .pop()
+        .map(Json)
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "no runs recorded yet".to_string()))
+}
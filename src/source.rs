@@ -0,0 +1,635 @@
+use crate::aggregator::Sample;
+use crate::client::ClientConfig;
+use crate::error::ClientError;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use reqwest::header::{HeaderMap, CONTENT_TYPE, RETRY_AFTER};
+use reqwest::StatusCode;
+use serde::{Deserialize, Deserializer};
+use sha2::Sha256;
+use std::fmt;
+use std::time::Duration;
+use thiserror::Error;
+use tracing::info;
+
+/// Why one attempt to read a price from a source failed.
+#[derive(Debug, Error)]
+pub enum FetchError {
+    #[error("{0}")]
+    Request(#[from] reqwest::Error),
+
+    /// The body was valid JSON but not a usable price, e.g. a malformed amount.
+    #[error("invalid response: {0}")]
+    InvalidResponse(serde_json::Error),
+
+    /// The body was not JSON at all, typically a truncated read or an HTML error page.
+    #[error("malformed response body: {0}")]
+    Decode(serde_json::Error),
+
+    /// Any other non-success status; the body is not even looked at.
+    #[error("HTTP {0}")]
+    Status(StatusCode),
+
+    /// A success status, but the body is declared as something other than JSON, e.g. an HTML
+    /// page from a captive portal.
+    #[error("unexpected content type '{0}'")]
+    ContentType(String),
+
+    /// HTTP 429; `retry_after` is the server's `Retry-After`, when it sent a usable one.
+    #[error("rate limited (HTTP 429)")]
+    RateLimited { retry_after: Option<Duration> },
+}
+
+impl From<serde_json::Error> for FetchError {
+    fn from(e: serde_json::Error) -> Self {
+        if e.is_data() {
+            FetchError::InvalidResponse(e)
+        } else {
+            FetchError::Decode(e)
+        }
+    }
+}
+
+impl FetchError {
+    /// Connection failures, timeouts and decode failures may clear up on a retry; a malformed
+    /// amount won't. Client errors other than 429 will fail the same way again, as will a
+    /// request reqwest could not even build.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            FetchError::Request(e) => e.is_connect() || e.is_timeout() || e.is_request() || e.is_body(),
+            FetchError::InvalidResponse(_) => false,
+            FetchError::Status(status) => !status.is_client_error(),
+            _ => true,
+        }
+    }
+
+    /// The API refused our credentials or access outright, or the request can't be built at
+    /// all; every later tick would fail the same way, so the client stops instead.
+    pub fn is_fatal(&self) -> bool {
+        match self {
+            FetchError::Status(status) => matches!(*status, StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN),
+            FetchError::Request(e) => e.is_builder(),
+            _ => false,
+        }
+    }
+
+    /// A short label for what went wrong, for the retry logs.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            FetchError::Request(e) => request_error_kind(e),
+            FetchError::InvalidResponse(_) => "invalid price",
+            FetchError::Decode(_) => "malformed body",
+            FetchError::Status(status) if status.is_server_error() => "server error",
+            FetchError::Status(_) => "client error",
+            FetchError::ContentType(_) => "unexpected content type",
+            FetchError::RateLimited { .. } => "rate limited",
+        }
+    }
+
+    /// How long the server asked us to wait before trying again.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            FetchError::RateLimited { retry_after } => *retry_after,
+            _ => None,
+        }
+    }
+}
+
+/// What kind of failure reqwest's catch-all error is, since one type covers DNS, resets,
+/// timeouts and requests that were never valid.
+pub fn request_error_kind(e: &reqwest::Error) -> &'static str {
+    if e.is_connect() {
+        "connection failure"
+    } else if e.is_timeout() {
+        "timeout"
+    } else if e.is_request() || e.is_body() {
+        "interrupted request"
+    } else if e.is_builder() {
+        "invalid request"
+    } else {
+        "request error"
+    }
+}
+
+/// `Retry-After` as either delay-seconds or an HTTP-date; a date in the past means now.
+fn parse_retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    Some((date.with_timezone(&Utc) - Utc::now()).to_std().unwrap_or(Duration::ZERO))
+}
+
+/// A provider that can be asked for the current spot price.
+#[async_trait]
+pub trait PriceSource: Send + Sync {
+    /// Short label recorded against every sample this source supplies.
+    fn name(&self) -> &str;
+
+    async fn fetch_price(&self, client: &reqwest::Client) -> Result<Sample, FetchError>;
+}
+
+/// Pulls the price, and the volume where the API has one, out of a provider's response body,
+/// so a source can point at any API.
+pub trait PriceParser: Send + Sync {
+    fn parse_sample(&self, body: &str) -> Result<Sample, FetchError>;
+}
+
+/// The response layouts `--parser` can select.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ResponseShape {
+    /// Coinbase's `{"data": {"amount": "..."}}`.
+    #[default]
+    Coinbase,
+    /// A top-level `{"price": ...}`, as a number or a decimal string, with an optional
+    /// `"volume"` in the same encoding.
+    Flat,
+}
+
+impl ResponseShape {
+    pub fn parser(self) -> Box<dyn PriceParser> {
+        match self {
+            ResponseShape::Coinbase => Box::new(CoinbaseParser),
+            ResponseShape::Flat => Box::new(FlatParser),
+        }
+    }
+}
+
+pub struct CoinbaseParser;
+
+#[derive(Debug, Deserialize)]
+struct CoinbaseResponse {
+    data: Data,
+}
+
+#[derive(Debug, Deserialize)]
+struct Data {
+    #[serde(deserialize_with = "deserialize_amount")]
+    amount: f64,
+}
+
+impl PriceParser for CoinbaseParser {
+    /// The spot endpoint reports no volume.
+    fn parse_sample(&self, body: &str) -> Result<Sample, FetchError> {
+        let message: CoinbaseResponse = serde_json::from_str(body)?;
+        Ok(Sample::price(message.data.amount))
+    }
+}
+
+pub struct FlatParser;
+
+#[derive(Debug, Deserialize)]
+struct FlatResponse {
+    #[serde(deserialize_with = "deserialize_price")]
+    price: f64,
+    #[serde(default, deserialize_with = "deserialize_volume")]
+    volume: Option<f64>,
+}
+
+impl PriceParser for FlatParser {
+    fn parse_sample(&self, body: &str) -> Result<Sample, FetchError> {
+        let message: FlatResponse = serde_json::from_str(body)?;
+        Ok(Sample {
+            price: message.price,
+            volume: message.volume,
+        })
+    }
+}
+
+/// An optional traded volume, in either encoding `deserialize_number_or_amount` reads; `null`
+/// is no volume. A negative one is rejected, as it can't weigh a price.
+pub fn deserialize_volume<'de, D>(deserializer: D) -> Result<Option<f64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    struct Volume(#[serde(deserialize_with = "deserialize_number_or_amount")] f64);
+
+    match Option::<Volume>::deserialize(deserializer)? {
+        Some(Volume(volume)) if volume < 0.0 => Err(serde::de::Error::custom(format!("negative volume {}", volume))),
+        volume => Ok(volume.map(|Volume(volume)| volume)),
+    }
+}
+
+/// A JSON number, or a decimal string as `parse_amount` reads it.
+fn deserialize_number_or_amount<'de, D>(deserializer: D) -> Result<f64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::Number(number) => number
+            .as_f64()
+            .ok_or_else(|| serde::de::Error::custom(format!("unrepresentable amount {}", number))),
+        serde_json::Value::String(raw) => parse_amount(&raw).map_err(serde::de::Error::custom),
+        other => Err(serde::de::Error::custom(format!("expected a number or decimal string, got {}", other))),
+    }
+}
+
+/// A price in either encoding `deserialize_number_or_amount` reads, held to `positive_price`.
+fn deserialize_price<'de, D>(deserializer: D) -> Result<f64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let price = deserialize_number_or_amount(deserializer)?;
+    positive_price(&price.to_string(), price).map_err(serde::de::Error::custom)
+}
+
+/// Coinbase sends amounts as decimal strings; the raw text is kept in the error for logging.
+/// Shared with the WebSocket ticker, whose prices use the same encoding. Other providers'
+/// `$43,210.55` style is read too, see `normalize_amount`.
+pub fn deserialize_amount<'de, D>(deserializer: D) -> Result<f64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let raw = String::deserialize(deserializer)?;
+    parse_amount(&raw)
+        .and_then(|amount| positive_price(&raw, amount))
+        .map_err(serde::de::Error::custom)
+}
+
+/// `raw` as a finite number once normalized. `parse` alone would take `NaN` and `inf`, which
+/// would then poison every average they reach.
+fn parse_amount(raw: &str) -> Result<f64, String> {
+    let normalized =
+        normalize_amount(raw).ok_or_else(|| format!("unparseable amount '{}': misplaced thousands separator", raw))?;
+    match normalized.parse::<f64>() {
+        Ok(amount) if amount.is_finite() => Ok(amount),
+        Ok(_) => Err(format!("unparseable amount '{}': not a finite number", raw)),
+        Err(e) => Err(format!("unparseable amount '{}': {}", raw, e)),
+    }
+}
+
+/// A price of zero or less is a glitch, not a reading, so the tick fails instead of dragging
+/// the aggregate and the price alerts down.
+fn positive_price(raw: &str, price: f64) -> Result<f64, String> {
+    if price > 0.0 {
+        Ok(price)
+    } else {
+        Err(format!("unparseable amount '{}': not a positive price", raw))
+    }
+}
+
+/// Symbols a provider may put before or after an amount.
+const CURRENCY_SYMBOLS: &[char] = &['$', '€', '£', '¥', '₿'];
+
+/// The amount with surrounding whitespace, currency symbols and thousands separators removed,
+/// e.g. `$43,210.55` becomes `43210.55`. Commas are only taken as separators between groups of
+/// three digits before the decimal point, so `4,3210` or `43.210,55` gives `None` rather than a
+/// different price; anything else that is not a number is left for `parse` to reject.
+fn normalize_amount(raw: &str) -> Option<String> {
+    let trimmed = raw.trim();
+    let (sign, unsigned) = match trimmed.strip_prefix('-') {
+        Some(rest) => ("-", rest),
+        None => ("", trimmed),
+    };
+    let number = unsigned.trim_matches(CURRENCY_SYMBOLS).trim();
+
+    let (whole, fraction) = match number.split_once('.') {
+        Some((whole, fraction)) => (whole, Some(fraction)),
+        None => (number, None),
+    };
+    if fraction.is_some_and(|fraction| fraction.contains(',')) {
+        return None;
+    }
+    if whole.contains(',') {
+        let mut groups = whole.split(',');
+        let leading = groups.next()?;
+        if leading.is_empty() || leading.len() > 3 || groups.any(|group| group.len() != 3) {
+            return None;
+        }
+    }
+
+    let whole = whole.replace(',', "");
+    Some(match fraction {
+        Some(fraction) => format!("{}{}.{}", sign, whole, fraction),
+        None => format!("{}{}", sign, whole),
+    })
+}
+
+async fn fetch_and_parse(request: reqwest::RequestBuilder, parser: &dyn PriceParser) -> Result<Sample, FetchError> {
+    let body = fetch_body(request).await?;
+
+    parser.parse_sample(&body)
+}
+
+/// The body of a successful JSON response, with rate limits and error statuses turned into
+/// the matching `FetchError`.
+pub async fn fetch_body(request: reqwest::RequestBuilder) -> Result<String, FetchError> {
+    let response = request.send().await?;
+    if response.status() == StatusCode::TOO_MANY_REQUESTS {
+        return Err(FetchError::RateLimited {
+            retry_after: parse_retry_after(response.headers()),
+        });
+    }
+    if !response.status().is_success() {
+        return Err(FetchError::Status(response.status()));
+    }
+    // A missing header is let through; only a body declared as something else is refused
+    if let Some(content_type) = response.headers().get(CONTENT_TYPE) {
+        let content_type = content_type.to_str().unwrap_or_default();
+        if !content_type.contains("json") {
+            return Err(FetchError::ContentType(content_type.to_string()));
+        }
+    }
+
+    Ok(response.text().await?)
+}
+
+/// Coinbase API key and secret from `COINBASE_API_KEY`/`COINBASE_API_SECRET`, for the
+/// authenticated, higher-rate limits. `Debug` never shows either value.
+#[derive(Clone)]
+pub struct ApiCredentials {
+    key: String,
+    secret: String,
+}
+
+impl fmt::Debug for ApiCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ApiCredentials(***)")
+    }
+}
+
+impl ApiCredentials {
+    /// `None` when no key is set, so requests stay anonymous exactly as before.
+    pub fn from_env() -> Result<Option<Self>, ClientError> {
+        let key = std::env::var("COINBASE_API_KEY").ok().filter(|key| !key.is_empty());
+        let secret = std::env::var("COINBASE_API_SECRET").ok().filter(|secret| !secret.is_empty());
+
+        let (key, secret) = match (key, secret) {
+            (None, None) => return Ok(None),
+            (Some(key), Some(secret)) => (key, secret),
+            (Some(_), None) => {
+                return Err(ClientError::Config(
+                    "COINBASE_API_KEY is set but COINBASE_API_SECRET is not; both are needed to sign requests"
+                        .to_string(),
+                ))
+            }
+            (None, Some(_)) => {
+                return Err(ClientError::Config(
+                    "COINBASE_API_SECRET is set but COINBASE_API_KEY is not".to_string(),
+                ))
+            }
+        };
+        for (name, value) in [("COINBASE_API_KEY", &key), ("COINBASE_API_SECRET", &secret)] {
+            if !value.chars().all(|c| c.is_ascii_graphic()) {
+                return Err(ClientError::Config(format!(
+                    "{} is malformed: it must be printable ASCII without spaces",
+                    name
+                )));
+            }
+        }
+
+        info!("Signing Coinbase requests with the API key from COINBASE_API_KEY");
+        Ok(Some(ApiCredentials { key, secret }))
+    }
+
+    /// Adds Coinbase's `CB-ACCESS-*` headers, signing the timestamp, method and path.
+    fn sign(&self, request: reqwest::RequestBuilder, path: &str) -> reqwest::RequestBuilder {
+        let timestamp = Utc::now().timestamp().to_string();
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.secret.as_bytes()).expect("HMAC accepts keys of any length");
+        mac.update(format!("{}GET{}", timestamp, path).as_bytes());
+        let signature = hex::encode(mac.finalize().into_bytes());
+
+        request
+            .header("CB-ACCESS-KEY", sensitive(&self.key))
+            .header("CB-ACCESS-SIGN", signature)
+            .header("CB-ACCESS-TIMESTAMP", timestamp)
+    }
+}
+
+/// A header value reqwest leaves out of its `Debug` output.
+fn sensitive(value: &str) -> reqwest::header::HeaderValue {
+    let mut value = reqwest::header::HeaderValue::from_str(value).expect("validated as printable ASCII");
+    value.set_sensitive(true);
+    value
+}
+
+/// The Coinbase `/v2/prices/{asset}-{currency}/spot` endpoint.
+pub struct CoinbaseSource {
+    url: String,
+    timeout: Duration,
+    parser: Box<dyn PriceParser>,
+    /// Only Coinbase sees these, never the fallback.
+    credentials: Option<ApiCredentials>,
+}
+
+impl CoinbaseSource {
+    pub fn new(config: &ClientConfig) -> Self {
+        CoinbaseSource {
+            url: config.spot_url(),
+            timeout: config.timeout,
+            parser: config.parser.parser(),
+            credentials: config.credentials.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl PriceSource for CoinbaseSource {
+    fn name(&self) -> &str {
+        "coinbase"
+    }
+
+    async fn fetch_price(&self, client: &reqwest::Client) -> Result<Sample, FetchError> {
+        let request = client.get(&self.url).timeout(self.timeout);
+        let request = match &self.credentials {
+            Some(credentials) => {
+                let path = reqwest::Url::parse(&self.url).map(|url| url.path().to_string()).unwrap_or_default();
+                credentials.sign(request, &path)
+            }
+            None => request,
+        };
+        fetch_and_parse(request, self.parser.as_ref()).await
+    }
+}
+
+/// A secondary provider at an arbitrary URL, read with `--fallback-parser`.
+/// `{asset}` and `{currency}` in the URL template are substituted per run.
+pub struct FallbackSource {
+    url: String,
+    timeout: Duration,
+    parser: Box<dyn PriceParser>,
+}
+
+impl FallbackSource {
+    pub fn new(url_template: &str, config: &ClientConfig) -> Self {
+        FallbackSource {
+            url: url_template
+                .replace("{asset}", &config.asset)
+                .replace("{currency}", &config.currency),
+            timeout: config.timeout,
+            parser: config.fallback_parser.parser(),
+        }
+    }
+}
+
+#[async_trait]
+impl PriceSource for FallbackSource {
+    fn name(&self) -> &str {
+        "fallback"
+    }
+
+    async fn fetch_price(&self, client: &reqwest::Client) -> Result<Sample, FetchError> {
+        fetch_and_parse(client.get(&self.url).timeout(self.timeout), self.parser.as_ref()).await
+    }
+}
+
+/// Sources a client tries each tick, primary first.
+pub fn build_sources(config: &ClientConfig) -> Vec<Box<dyn PriceSource>> {
+    let mut sources: Vec<Box<dyn PriceSource>> = vec![Box::new(CoinbaseSource::new(config))];
+    if let Some(template) = &config.fallback_url {
+        sources.push(Box::new(FallbackSource::new(template, config)));
+    }
+    sources
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{dead_url, MockResponse, MockServer};
+
+    fn coinbase_body(amount: &str) -> String {
+        format!(r#"{{"data":{{"amount":"{}","base":"BTC","currency":"USD"}}}}"#, amount)
+    }
+
+    #[test]
+    fn a_non_numeric_amount_is_an_invalid_response_and_not_retried() {
+        let err = CoinbaseParser.parse_sample(&coinbase_body("not_a_number")).unwrap_err();
+
+        assert!(matches!(err, FetchError::InvalidResponse(_)), "got {:?}", err);
+        assert!(err.to_string().contains("not_a_number"));
+        assert!(!err.is_retryable());
+    }
+
+    #[test]
+    fn the_flat_parser_reads_a_volume_in_either_encoding_or_none() {
+        let sample = |body: &str| FlatParser.parse_sample(body).expect("a valid flat body parses");
+
+        assert_eq!(sample(r#"{"price":100.5,"volume":2}"#), Sample { price: 100.5, volume: Some(2.0) });
+        assert_eq!(sample(r#"{"price":"100.5","volume":"0.25"}"#), Sample { price: 100.5, volume: Some(0.25) });
+        assert_eq!(sample(r#"{"price":100.5,"volume":null}"#), Sample::price(100.5));
+        assert_eq!(sample(r#"{"price":100.5}"#), Sample::price(100.5));
+    }
+
+    #[test]
+    fn the_flat_parser_rejects_a_negative_volume() {
+        let err = FlatParser.parse_sample(r#"{"price":100.5,"volume":-1}"#).unwrap_err();
+
+        assert!(err.to_string().contains("negative volume"), "got {}", err);
+        assert!(!err.is_retryable());
+    }
+
+    fn retry_after(value: &str) -> Option<Duration> {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, value.parse().expect("valid header value"));
+        parse_retry_after(&headers)
+    }
+
+    #[test]
+    fn retry_after_is_read_as_seconds_or_a_date() {
+        assert_eq!(retry_after("5"), Some(Duration::from_secs(5)));
+        assert_eq!(retry_after(" 0 "), Some(Duration::ZERO));
+        // A date already past means no wait at all
+        assert_eq!(retry_after("Wed, 21 Oct 2015 07:28:00 GMT"), Some(Duration::ZERO));
+        let later = (Utc::now() + chrono::Duration::seconds(30)).to_rfc2822();
+        assert!(retry_after(&later).is_some_and(|wait| wait > Duration::from_secs(25)));
+    }
+
+    #[test]
+    fn an_unusable_retry_after_is_ignored() {
+        assert_eq!(retry_after("soon"), None);
+        assert_eq!(retry_after("-1"), None);
+        assert_eq!(parse_retry_after(&HeaderMap::new()), None);
+    }
+
+    async fn fetch_from(response: MockResponse) -> Result<String, FetchError> {
+        let server = MockServer::start(vec![response]).await;
+        fetch_body(reqwest::Client::new().get(server.url())).await
+    }
+
+    #[tokio::test]
+    async fn an_error_status_is_reported_without_reading_an_html_body() {
+        let err = fetch_from(MockResponse::html(500, "<html><body>Internal Server Error</body></html>"))
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, FetchError::Status(StatusCode::INTERNAL_SERVER_ERROR)), "got {:?}", err);
+        assert!(err.is_retryable());
+    }
+
+    #[tokio::test]
+    async fn a_success_that_is_not_json_is_refused_by_content_type() {
+        let err = fetch_from(MockResponse::html(200, "<html>Sign in to the network</html>"))
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, FetchError::ContentType(ref content_type) if content_type.starts_with("text/html")));
+    }
+
+    #[tokio::test]
+    async fn a_json_success_or_one_without_a_content_type_is_read() {
+        let body = fetch_from(MockResponse::price("100.00")).await.expect("JSON is read");
+        assert!(body.contains("100.00"));
+
+        let untyped = fetch_from(MockResponse::status(200)).await;
+        assert_eq!(untyped.expect("a missing content type is let through"), "");
+    }
+
+    #[tokio::test]
+    async fn a_refused_connection_is_retryable() {
+        let err = fetch_body(reqwest::Client::new().get(dead_url().await)).await.unwrap_err();
+
+        assert!(matches!(err, FetchError::Request(ref e) if e.is_connect()), "got {:?}", err);
+        assert_eq!(err.kind(), "connection failure");
+        assert!(err.is_retryable());
+        assert!(!err.is_fatal());
+    }
+
+    #[tokio::test]
+    async fn a_request_that_cannot_be_built_is_fatal() {
+        let err = fetch_body(reqwest::Client::new().get("http://exa mple.com/spot")).await.unwrap_err();
+
+        assert!(matches!(err, FetchError::Request(ref e) if e.is_builder()), "got {:?}", err);
+        assert_eq!(err.kind(), "invalid request");
+        assert!(!err.is_retryable());
+        assert!(err.is_fatal());
+    }
+
+    #[test]
+    fn amounts_in_other_providers_formats_are_normalized() {
+        for (amount, price) in [
+            ("43210.55", 43210.55),
+            ("43,210.55", 43210.55),
+            ("$43210", 43210.0),
+            ("$1,043,210.55", 1043210.55),
+            (" 43210.55 € ", 43210.55),
+        ] {
+            let sample = CoinbaseParser.parse_sample(&coinbase_body(amount));
+            assert_eq!(sample.map(|sample| sample.price).ok(), Some(price), "{:?}", amount);
+        }
+        // Normalizing must not let through what parse::<f64>() alone takes but no price can be
+        for amount in ["NaN", "inf", "infinity", "-1,000.5", "0"] {
+            let err = CoinbaseParser.parse_sample(&coinbase_body(amount)).unwrap_err();
+            assert!(matches!(err, FetchError::InvalidResponse(_)), "{:?}: {:?}", amount, err);
+            assert!(err.to_string().contains(&format!("unparseable amount '{}'", amount)), "{:?}: {}", amount, err);
+        }
+    }
+
+    #[test]
+    fn amounts_that_are_not_numbers_or_misplace_separators_are_rejected() {
+        for (amount, reason) in [
+            ("abc", "unparseable amount 'abc'"),
+            ("", "unparseable amount ''"),
+            ("4,3210", "misplaced thousands separator"),
+            ("43.210,55", "misplaced thousands separator"),
+            (",210.55", "misplaced thousands separator"),
+        ] {
+            let err = CoinbaseParser.parse_sample(&coinbase_body(amount)).unwrap_err();
+            assert!(matches!(err, FetchError::InvalidResponse(_)), "{:?}: {:?}", amount, err);
+            assert!(err.to_string().contains(reason), "{:?}: {}", amount, err);
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use crate::record::ResultRecord;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Summary of every recorded run for one asset/currency pair, for `--mode=stats`.
+#[derive(Debug, Serialize)]
+pub struct HistoryStats {
+    pub asset: String,
+    pub currency: String,
+    pub runs: usize,
+    /// Runs left out because they carried no fresh price (`stale` or `no_data`).
+    pub skipped: usize,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub mean: Option<f64>,
+    /// Population standard deviation of the run aggregates; needs at least two runs.
+    pub volatility: Option<f64>,
+    pub first_run: Option<DateTime<Utc>>,
+    pub last_run: Option<DateTime<Utc>>,
+}
+
+impl HistoryStats {
+    /// One summary per asset/currency pair, since averaging BTC and ETH aggregates together
+    /// would mean nothing.
+    pub fn from_records(records: &[ResultRecord]) -> Vec<HistoryStats> {
+        let mut pairs: BTreeMap<(&str, &str), Vec<&ResultRecord>> = BTreeMap::new();
+        for record in records {
+            pairs
+                .entry((record.asset.as_str(), record.currency.as_str()))
+                .or_default()
+                .push(record);
+        }
+
+        pairs
+            .into_iter()
+            .map(|((asset, currency), records)| HistoryStats::for_pair(asset, currency, &records))
+            .collect()
+    }
+
+    fn for_pair(asset: &str, currency: &str, records: &[&ResultRecord]) -> HistoryStats {
+        let fresh: Vec<&ResultRecord> = records.iter().copied().filter(|r| !r.stale && !r.no_data).collect();
+        let aggregates: Vec<f64> = fresh.iter().map(|record| record.aggregate).collect();
+        let mean = (!aggregates.is_empty()).then(|| aggregates.iter().sum::<f64>() / aggregates.len() as f64);
+        let volatility = mean.filter(|_| aggregates.len() >= 2).map(|mean| {
+            let variance =
+                aggregates.iter().map(|aggregate| (aggregate - mean).powi(2)).sum::<f64>() / aggregates.len() as f64;
+            variance.sqrt()
+        });
+        let timestamps = fresh.iter().filter_map(|record| record.timestamp);
+
+        HistoryStats {
+            asset: asset.to_string(),
+            currency: currency.to_string(),
+            runs: fresh.len(),
+            skipped: records.len() - fresh.len(),
+            min: aggregates.iter().copied().reduce(f64::min),
+            max: aggregates.iter().copied().reduce(f64::max),
+            mean,
+            volatility,
+            first_run: timestamps.clone().min(),
+            last_run: timestamps.max(),
+        }
+    }
+}
+
+impl fmt::Display for HistoryStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} in {}: {} runs", self.asset, self.currency, self.runs)?;
+        if self.skipped > 0 {
+            writeln!(f, "  Skipped: {} stale or no-data runs", self.skipped)?;
+        }
+        let (Some(min), Some(max), Some(mean)) = (self.min, self.max, self.mean) else {
+            return writeln!(f, "  No runs with a fresh price yet.");
+        };
+        if self.runs == 1 {
+            return writeln!(f, "  Aggregate: {} (one run, nothing to compare)", mean);
+        }
+        writeln!(f, "  Min: {}", min)?;
+        writeln!(f, "  Max: {}", max)?;
+        writeln!(f, "  Mean: {}", mean)?;
+        if let Some(volatility) = self.volatility {
+            writeln!(f, "  Volatility (std dev): {}", volatility)?;
+        }
+        if let (Some(first), Some(last)) = (self.first_run, self.last_run) {
+            writeln!(f, "  Covering: {} to {}", first.to_rfc3339(), last.to_rfc3339())?;
+        }
+        Ok(())
+    }
+}
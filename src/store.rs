@@ -0,0 +1,82 @@
+use crate::error::ClientError;
+use crate::record::ResultRecord;
+use rusqlite::{params, Connection};
+use std::path::Path;
+
+/// Runs `read_mode` prints from the database, newest last.
+pub const RECENT_RUNS: usize = 20;
+
+/// Opens the history database, creating the schema on first use.
+pub fn open(path: &Path) -> Result<Connection, ClientError> {
+    let conn = Connection::open(path)?;
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS runs (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            timestamp TEXT,
+            asset TEXT NOT NULL,
+            currency TEXT NOT NULL,
+            aggregate REAL NOT NULL,
+            samples INTEGER NOT NULL,
+            record TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS client_reports (
+            run_id INTEGER NOT NULL REFERENCES runs(id),
+            client_id INTEGER NOT NULL,
+            average REAL NOT NULL,
+            samples INTEGER NOT NULL,
+            PRIMARY KEY (run_id, client_id)
+        );",
+    )?;
+
+    Ok(conn)
+}
+
+/// Inserts one run and its per-client breakdown. The full record is kept as JSON alongside the
+/// queryable columns so reading it back loses nothing.
+pub fn insert_run(path: &Path, record: &ResultRecord) -> Result<(), ClientError> {
+    let mut conn = open(path)?;
+    let tx = conn.transaction()?;
+
+    tx.execute(
+        "INSERT INTO runs (timestamp, asset, currency, aggregate, samples, record) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        params![
+            record.timestamp.map(|t| t.to_rfc3339()),
+            record.asset,
+            record.currency,
+            record.aggregate,
+            record.samples as i64,
+            serde_json::to_string(record)?,
+        ],
+    )?;
+    let run_id = tx.last_insert_rowid();
+
+    for report in &record.per_client {
+        tx.execute(
+            "INSERT INTO client_reports (run_id, client_id, average, samples) VALUES (?1, ?2, ?3, ?4)",
+            params![run_id, report.client_id as i64, report.average, report.samples as i64],
+        )?;
+    }
+
+    tx.commit()?;
+    Ok(())
+}
+
+/// The latest `limit` runs, oldest first.
+pub fn recent_runs(path: &Path, limit: usize) -> Result<Vec<ResultRecord>, ClientError> {
+    let conn = open(path)?;
+    let mut statement = conn.prepare("SELECT record FROM runs ORDER BY id DESC LIMIT ?1")?;
+    let rows = statement.query_map(params![limit as i64], |row| row.get::<_, String>(0))?;
+
+    let mut records = Vec::new();
+    for row in rows {
+        records.push(serde_json::from_str::<ResultRecord>(&row?)?);
+    }
+    records.reverse();
+
+    Ok(records)
+}
+
+/// Every recorded run, oldest first.
+pub fn all_runs(path: &Path) -> Result<Vec<ResultRecord>, ClientError> {
+    recent_runs(path, i64::MAX as usize)
+}
@@ -0,0 +1,339 @@
+use crate::aggregator::ClientMessage;
+use crate::client::{
+    build_http_client, simulate_client, Averaging, ClientConfig, SampleOutput, SharedState, SyntheticConfig, Transport,
+    COINBASE_WS_FEED,
+};
+use crate::cli::OutputFormat;
+use crate::error::ClientError;
+use crate::output::{Console, OutputConfig};
+use crate::source::ResponseShape;
+use axum::body::Body;
+use axum::extract::{ConnectInfo, State};
+use axum::http::{header, StatusCode};
+use axum::response::Response;
+use axum::Router;
+use futures_util::future::join_all;
+use std::collections::{HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tokio::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+/// One canned answer from a `MockServer`.
+#[derive(Debug, Clone)]
+pub struct MockResponse {
+    status: u16,
+    content_type: Option<String>,
+    body: String,
+    headers: Vec<(String, String)>,
+    delay: Duration,
+}
+
+impl MockResponse {
+    /// A Coinbase spot response carrying `amount`, verbatim.
+    pub fn price(amount: &str) -> Self {
+        MockResponse::json(&format!(
+            r#"{{"data":{{"amount":"{}","base":"BTC","currency":"USD"}}}}"#,
+            amount
+        ))
+    }
+
+    /// A 200 with `body` declared as JSON, whether or not it is.
+    pub fn json(body: &str) -> Self {
+        MockResponse {
+            status: 200,
+            content_type: Some("application/json".to_string()),
+            body: body.to_string(),
+            headers: Vec::new(),
+            delay: Duration::ZERO,
+        }
+    }
+
+    /// An HTML page with `status`, like a proxy's or load balancer's error page.
+    pub fn html(status: u16, body: &str) -> Self {
+        MockResponse {
+            status,
+            content_type: Some("text/html; charset=utf-8".to_string()),
+            ..MockResponse::json(body)
+        }
+    }
+
+    /// `status` with an empty body.
+    pub fn status(status: u16) -> Self {
+        MockResponse {
+            status,
+            content_type: None,
+            body: String::new(),
+            headers: Vec::new(),
+            delay: Duration::ZERO,
+        }
+    }
+
+    /// The same answer with one more header.
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// The same answer, sent only after `delay`.
+    pub fn delayed(self, delay: Duration) -> Self {
+        MockResponse { delay, ..self }
+    }
+}
+
+#[derive(Debug)]
+struct MockState {
+    /// Answers still to give, in order; the last one repeats once the rest are used up.
+    responses: Mutex<VecDeque<MockResponse>>,
+    requests: AtomicUsize,
+    in_flight: AtomicUsize,
+    max_in_flight: AtomicUsize,
+    /// Client ends of the TCP connections requests arrived on.
+    peers: Mutex<HashSet<SocketAddr>>,
+}
+
+impl MockState {
+    fn next_response(&self) -> MockResponse {
+        let mut responses = self.responses.lock().expect("mock responses lock poisoned");
+        match responses.len() {
+            0 => MockResponse::status(500),
+            1 => responses[0].clone(),
+            _ => responses.pop_front().expect("checked non-empty"),
+        }
+    }
+}
+
+/// A price API on `127.0.0.1` that answers every request, whatever its path, with the next of
+/// its canned responses, so clients can be run against known prices and failures offline.
+pub struct MockServer {
+    url: String,
+    state: Arc<MockState>,
+}
+
+impl MockServer {
+    pub async fn start(responses: Vec<MockResponse>) -> Self {
+        let state = Arc::new(MockState {
+            responses: Mutex::new(responses.into()),
+            requests: AtomicUsize::new(0),
+            in_flight: AtomicUsize::new(0),
+            max_in_flight: AtomicUsize::new(0),
+            peers: Mutex::new(HashSet::new()),
+        });
+        let app = Router::new().fallback(respond).with_state(state.clone());
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("mock server binds");
+        let url = format!("http://{}", listener.local_addr().expect("mock server address"));
+        tokio::spawn(async move { axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await });
+
+        MockServer { url, state }
+    }
+
+    /// Base URL to use as a client's `base_url`.
+    pub fn url(&self) -> String {
+        self.url.clone()
+    }
+
+    /// Requests answered so far.
+    pub fn requests(&self) -> usize {
+        self.state.requests.load(Ordering::SeqCst)
+    }
+
+    /// Most requests the server was answering at once.
+    pub fn max_in_flight(&self) -> usize {
+        self.state.max_in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Distinct TCP connections those requests arrived on.
+    pub fn connections(&self) -> usize {
+        self.state.peers.lock().expect("mock peers lock poisoned").len()
+    }
+}
+
+async fn respond(State(state): State<Arc<MockState>>, ConnectInfo(peer): ConnectInfo<SocketAddr>) -> Response {
+    state.peers.lock().expect("mock peers lock poisoned").insert(peer);
+    let response = state.next_response();
+    state.requests.fetch_add(1, Ordering::SeqCst);
+
+    let in_flight = state.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+    state.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+    tokio::time::sleep(response.delay).await;
+    state.in_flight.fetch_sub(1, Ordering::SeqCst);
+
+    let mut builder = Response::builder().status(StatusCode::from_u16(response.status).expect("valid mock status"));
+    if let Some(content_type) = &response.content_type {
+        builder = builder.header(header::CONTENT_TYPE, content_type);
+    }
+    for (name, value) in &response.headers {
+        builder = builder.header(name, value);
+    }
+    builder.body(Body::from(response.body)).expect("valid mock response")
+}
+
+/// A URL on `127.0.0.1` whose port nothing listens on, so connecting to it is refused.
+pub async fn dead_url() -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("free port binds");
+    let addr = listener.local_addr().expect("free port address");
+    drop(listener);
+    format!("http://{}", addr)
+}
+
+/// A fresh directory under the system temp dir, removed with everything in it on drop.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new() -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "simulated_distributed_client-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::SeqCst)
+        ));
+        std::fs::create_dir_all(&path).expect("temp dir is creatable");
+        TempDir { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// `contents` written to `name` in the directory.
+    pub fn file(&self, name: &str, contents: &str) -> PathBuf {
+        let path = self.path.join(name);
+        std::fs::write(&path, contents).expect("temp file is writable");
+        path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+/// A one-second, 100ms-interval client against `base_url` with every optional feature off.
+/// The breaker threshold is out of reach, so only tests that lower it see the breaker trip.
+pub fn test_config(base_url: &str) -> ClientConfig {
+    ClientConfig {
+        base_url: base_url.to_string(),
+        times: 1,
+        once: false,
+        max_samples: None,
+        asset: "BTC".to_string(),
+        currency: "USD".to_string(),
+        timeout: Duration::from_secs(5),
+        total_timeout: Duration::from_secs(31),
+        proxy: None,
+        credentials: None,
+        headers: Vec::new(),
+        interval: Duration::from_millis(100),
+        averaging: Averaging::Mean,
+        window: None,
+        transport: Transport::Http,
+        ws_url: COINBASE_WS_FEED.to_string(),
+        parser: ResponseShape::Coinbase,
+        fallback_url: None,
+        fallback_parser: ResponseShape::Coinbase,
+        compare_sources: None,
+        max_concurrent: None,
+        rate: None,
+        cache_ttl: None,
+        warmup: Duration::ZERO,
+        breaker_threshold: 1000,
+        breaker_cooldown: Duration::from_secs(5),
+        jitter_seed: None,
+        vary_seed: None,
+        synthetic: None,
+        fail_fast: None,
+        samples: SampleOutput::Off,
+        precision: 2,
+    }
+}
+
+/// Quiet JSON output to `path`, replacing the file each run.
+pub fn file_output(path: PathBuf) -> OutputConfig {
+    OutputConfig {
+        path,
+        format: OutputFormat::Json,
+        append: false,
+        no_file: false,
+        precision: 2,
+        console: Console::Quiet,
+        progress: false,
+        metrics_addr: None,
+        db: None,
+    }
+}
+
+/// A `--dry-run` client generating prices around 100, so it makes no requests and, under a
+/// paused clock, runs its whole window in milliseconds.
+pub fn dry_run_config(seed: u64) -> ClientConfig {
+    ClientConfig {
+        synthetic: Some(SyntheticConfig { base_price: 100.0, seed }),
+        ..test_config("http://127.0.0.1:9")
+    }
+}
+
+/// Runs client 1 with `config` to the end of its window and returns what it returned and the
+/// message it sent the aggregator.
+pub async fn run_client(config: ClientConfig) -> (Result<(), ClientError>, ClientMessage) {
+    run_client_until(config, CancellationToken::new()).await
+}
+
+/// `run_client`, with the client stopping early once `shutdown` is cancelled.
+pub async fn run_client_until(
+    config: ClientConfig,
+    shutdown: CancellationToken,
+) -> (Result<(), ClientError>, ClientMessage) {
+    let http_client = build_http_client(&config).expect("test HTTP client builds");
+    let shared = SharedState::new(&config);
+    let (tx, mut rx) = mpsc::channel(1);
+
+    let result = simulate_client(1, config, http_client, Instant::now(), tx, shutdown, shared).await;
+    let message = rx.recv().await.expect("the client reports before returning");
+
+    (result, message)
+}
+
+/// Runs clients 1 to `clients` with `config`, sharing one HTTP client and `SharedState` as a
+/// cache run does, and returns what each sent the aggregator, in client order.
+pub async fn run_clients(config: ClientConfig, clients: usize) -> Vec<ClientMessage> {
+    let http_client = build_http_client(&config).expect("test HTTP client builds");
+    let shared = SharedState::new(&config);
+    let (tx, mut rx) = mpsc::channel(clients);
+    let start_time = Instant::now();
+
+    let runs = (1..=clients).map(|client_id| {
+        simulate_client(
+            client_id,
+            config.clone(),
+            http_client.clone(),
+            start_time,
+            tx.clone(),
+            CancellationToken::new(),
+            shared.clone(),
+        )
+    });
+    for result in join_all(runs).await {
+        result.expect("client succeeds");
+    }
+    drop(tx);
+
+    let mut messages = Vec::new();
+    while let Some(message) = rx.recv().await {
+        messages.push(message);
+    }
+    messages.sort_by_key(|message| match message {
+        ClientMessage::Report(report) => report.client_id,
+        ClientMessage::NoSamples { client_id, .. } => *client_id,
+    });
+    messages
+}
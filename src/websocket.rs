@@ -0,0 +1,100 @@
+use crate::aggregator::{Outcome, Sample};
+use crate::client::{ClientConfig, Tally};
+use crate::metrics::Metrics;
+use crate::source::{deserialize_amount, deserialize_volume};
+use futures_util::{SinkExt, StreamExt};
+use serde::Deserialize;
+use serde_json::json;
+use thiserror::Error;
+use tokio::time::Instant;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, warn};
+
+#[derive(Debug, Error)]
+pub enum StreamError {
+    #[error("{0}")]
+    WebSocket(#[from] tokio_tungstenite::tungstenite::Error),
+
+    #[error("timed out connecting to the feed")]
+    ConnectTimeout,
+
+    #[error("feed reported an error: {0}")]
+    Feed(String),
+
+    #[error("feed closed the connection")]
+    Closed,
+}
+
+/// The subset of Coinbase exchange feed messages we act on.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum FeedMessage {
+    Ticker {
+        #[serde(deserialize_with = "deserialize_amount")]
+        price: f64,
+        /// Size of the trade that set the price.
+        #[serde(default, deserialize_with = "deserialize_volume")]
+        last_size: Option<f64>,
+    },
+    Error {
+        message: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+/// Subscribes to the ticker channel and folds every pushed price into `tally` until the window
+/// ends or Ctrl+C. An error means the caller should poll over HTTP for whatever window remains.
+pub async fn stream_prices(
+    config: &ClientConfig,
+    start_time: Instant,
+    shutdown: &CancellationToken,
+    tally: &mut Tally,
+    metrics: &Metrics,
+) -> Result<(), StreamError> {
+    let (mut stream, _) = tokio::time::timeout(config.timeout, tokio_tungstenite::connect_async(&config.ws_url))
+        .await
+        .map_err(|_| StreamError::ConnectTimeout)??;
+
+    let product = format!("{}-{}", config.asset, config.currency);
+    let subscribe = json!({
+        "type": "subscribe",
+        "product_ids": [product],
+        "channels": ["ticker"],
+    });
+    stream.send(Message::Text(subscribe.to_string())).await?;
+    info!("Subscribed to the {} ticker feed", product);
+
+    while tally.window_open(config, start_time) {
+        let message = tokio::select! {
+            message = stream.next() => message,
+            _ = tokio::time::sleep_until(config.deadline(start_time)), if !config.once => break,
+            _ = shutdown.cancelled() => break,
+        };
+
+        let text = match message {
+            Some(Ok(Message::Text(text))) => text,
+            Some(Ok(Message::Close(_))) | None => return Err(StreamError::Closed),
+            Some(Ok(_)) => continue,
+            Some(Err(e)) => return Err(e.into()),
+        };
+
+        let in_warmup = start_time.elapsed() < config.warmup;
+        match serde_json::from_str::<FeedMessage>(&text) {
+            Ok(FeedMessage::Ticker { price, last_size }) => {
+                let sample = Sample { price, volume: last_size };
+                tally.record(Outcome::Ok, Some((sample, "websocket")), in_warmup, metrics)
+            }
+            Ok(FeedMessage::Error { message }) => return Err(StreamError::Feed(message)),
+            Ok(FeedMessage::Other) => debug!("Ignoring feed message: {}", text),
+            Err(e) => {
+                warn!("Discarding feed message: {}", e);
+                tally.record(Outcome::ParseError, None, in_warmup, metrics);
+            }
+        }
+    }
+
+    let _ = stream.close(None).await;
+    Ok(())
+}